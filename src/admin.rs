@@ -0,0 +1,128 @@
+use crate::health_checker::Command;
+use log::warn;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::Sender;
+use warp::{http, Filter};
+
+pub type StatusBoard = Arc<RwLock<Vec<TargetStatus>>>;
+pub type LeaderBoard = Arc<RwLock<LeaderStatus>>;
+
+/// Whether this replica changes DNS records, followers refuse drains and point to the leader instead
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaderStatus {
+    pub leading: bool,
+    /// Description of the leader, if known by a follower
+    pub leader: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    pub id: String,
+    pub ip: IpAddr,
+    pub zone: String,
+    pub dns: String,
+    /// Health according to the rise and fall thresholds, null until either has been reached
+    pub healthy: Option<bool>,
+    pub in_dns: bool,
+    pub drained: bool,
+    pub last_check_up: Option<bool>,
+    pub last_check_duration_ms: Option<f64>,
+    pub consecutive_successes: u32,
+    pub consecutive_failures: u32,
+    /// Unix timestamp in seconds of the last change of `healthy`
+    pub last_transition: Option<u64>,
+}
+
+/// `GET /targets`, `POST /targets/{id}/drain` and `POST /targets/{id}/undrain`.
+/// Drain and undrain are refused unless a token is configured, and by followers
+pub fn admin_filter(
+    status: StatusBoard,
+    leadership: LeaderBoard,
+    commands: Sender<Command>,
+    token: Option<String>,
+) -> impl warp::Filter<Extract=(impl warp::Reply, ), Error=warp::Rejection> + Clone {
+    if token.is_none() {
+        warn!("No ADMIN_TOKEN configured, drain and undrain are disabled");
+    }
+    let list_status = status.clone();
+    let list = warp::get()
+        .and(warp::path!("targets"))
+        .map(move || {
+            let status = list_status.read().unwrap().clone();
+            warp::reply::json(&status)
+        });
+
+    let drain = warp::post()
+        .and(warp::path!("targets" / String / "drain"))
+        .map(|id| (id, true))
+        .untuple_one();
+    let undrain = warp::post()
+        .and(warp::path!("targets" / String / "undrain"))
+        .map(|id| (id, false))
+        .untuple_one();
+    let command = drain.or(undrain)
+        .unify()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |id: String, drain: bool, authorization: Option<String>| {
+            let status = status.clone();
+            let leadership = leadership.clone();
+            let commands = commands.clone();
+            let token = token.clone();
+            async move {
+                let token = match token {
+                    Some(token) => { token }
+                    None => {
+                        return Ok::<_, warp::Rejection>(reply("drain and undrain require an admin token", http::StatusCode::FORBIDDEN));
+                    }
+                };
+                let expected = format!("Bearer {}", token);
+                let authorized = authorization
+                    .map(|authorization| constant_time_eq(authorization.as_bytes(), expected.as_bytes()))
+                    .unwrap_or(false);
+                if !authorized {
+                    return Ok(reply("unauthorized", http::StatusCode::UNAUTHORIZED));
+                }
+                let leadership = leadership.read().unwrap().clone();
+                if !leadership.leading {
+                    let message = serde_json::json!({
+                        "message": "this replica is a follower, please send drains to the leader",
+                        "leader": leadership.leader,
+                    });
+                    return Ok(warp::reply::with_status(warp::reply::json(&message), http::StatusCode::CONFLICT));
+                }
+                let exists = status.read().unwrap().iter()
+                    .any(|target| target.id.eq(&id));
+                if !exists {
+                    return Ok(reply("unknown target", http::StatusCode::NOT_FOUND));
+                }
+                let command = if drain {
+                    Command::Drain(id)
+                } else {
+                    Command::Undrain(id)
+                };
+                match commands.send(command).await {
+                    Ok(_) => { Ok(reply("accepted", http::StatusCode::ACCEPTED)) }
+                    Err(_) => { Ok(reply("health checker stopped", http::StatusCode::SERVICE_UNAVAILABLE)) }
+                }
+            }
+        });
+
+    list.or(command)
+}
+
+/// Compares without returning early, so the time taken doesn't reveal how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+fn reply(message: &str, status: http::StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "message": message })),
+        status,
+    )
+}
@@ -0,0 +1,88 @@
+use crate::metrics::TLS_CERTIFICATE_EXPIRY_DAYS;
+use crate::models::CertificateCheck;
+use log::warn;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Validates the leaf certificate presented by a target and exports its remaining validity
+pub fn check_certificate(der: &[u8], check: &CertificateCheck, target_host: &str, target_id: &str) -> bool {
+    let certificate = match parse_x509_certificate(der) {
+        Ok((_, certificate)) => { certificate }
+        Err(_) => {
+            warn!("Target {} presented a certificate which could not be parsed", target_host);
+            return false;
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+    let validity = certificate.validity();
+    let days_left = (validity.not_after.timestamp() - now) as f64 / SECONDS_PER_DAY;
+    TLS_CERTIFICATE_EXPIRY_DAYS
+        .with_label_values(&[target_host, target_id])
+        .set(days_left);
+
+    if validity.not_before.timestamp() > now {
+        warn!("Certificate of target {} is not valid yet", target_host);
+        return false;
+    }
+    if days_left <= 0.0 {
+        warn!("Certificate of target {} is expired", target_host);
+        return false;
+    }
+    if days_left < check.fail_days as f64 {
+        warn!("Certificate of target {} expires in {:.1} days", target_host, days_left);
+        return false;
+    }
+    if days_left < check.warn_days as f64 {
+        warn!("Certificate of target {} expires in {:.1} days", target_host, days_left);
+    }
+    if let Some(hostname) = &check.hostname {
+        if !matches_hostname(&certificate, hostname.as_str()) {
+            warn!("Certificate of target {} is not valid for {}", target_host, hostname);
+            return false;
+        }
+    }
+    true
+}
+
+/// Matches against the subject alternative names, or the common name if there are none
+fn matches_hostname(certificate: &X509Certificate, hostname: &str) -> bool {
+    let names = match certificate.subject_alternative_name() {
+        Ok(Some(extension)) => {
+            extension.value.general_names.iter()
+                .filter_map(|name| {
+                    match name {
+                        GeneralName::DNSName(dns) => { Some(dns.to_string()) }
+                        _ => { None }
+                    }
+                })
+                .collect::<Vec<String>>()
+        }
+        _ => {
+            certificate.subject().iter_common_name()
+                .filter_map(|common_name| common_name.as_str().ok())
+                .map(|common_name| common_name.to_owned())
+                .collect()
+        }
+    };
+    names.iter().any(|name| name_matches(name.as_str(), hostname))
+}
+
+/// Wildcards only match a single, complete left-most label
+fn name_matches(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let hostname = hostname.trim_end_matches('.').to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            hostname.split_once('.')
+                .map(|(label, rest)| !label.is_empty() && rest == suffix)
+                .unwrap_or(false)
+        }
+        None => { pattern == hostname }
+    }
+}
@@ -1,52 +1,410 @@
-use crate::models::{SerializedServiceTarget, ServiceTarget, SerializedServiceUri, ServiceUri};
-use std::net::Ipv4Addr;
+use crate::models::{SerializedServiceTarget, SerializedCombinator, CheckCombinator, ServiceTarget, TargetMode, SerializedServiceUri, ServiceUri, HttpCheck, StatusRange, SerializedStatusCode, BodyMatch, SerializedConfig, SerializedTlsConfig, TlsConfig, CertificateCheck, IcmpCheck, TcpCheck, GrpcCheck, DnsCheck, DnsProtocol, ExecCheck};
+use std::net::IpAddr;
 use std::str::FromStr;
-use warp::http::Method;
+use std::path::Path;
+use std::{env, fs};
+use tokio::time::Duration;
+use warp::http::{HeaderMap, HeaderValue, Method};
+use warp::http::header::{HeaderName, CONTENT_TYPE};
+use regex::Regex;
+use trust_dns_client::rr::{Name, RecordType};
 
-pub struct Config {}
+const DEFAULT_RISE: u32 = 2;
+const DEFAULT_FALL: u32 = 3;
+const DEFAULT_CERT_WARN_DAYS: u32 = 14;
+const DEFAULT_ICMP_COUNT: u32 = 3;
+const DEFAULT_ICMP_MAX_LOSS_PERCENT: f64 = 50.0;
+const DEFAULT_DNS_PORT: u16 = 53;
+
+/// Settings of the balancer, read from the optional config file and overridden by env
+pub struct Config {
+    pub service_targets: Vec<ServiceTarget>,
+    pub check_interval: Duration,
+    /// Amount of check intervals between reconciliations of the records, 0 disables them
+    pub reconcile_every: u32,
+    pub dns_provider: String,
+    /// Only logs the DNS changes instead of making them
+    pub dry_run: bool,
+    /// Keeps the health of the targets across restarts if set
+    pub state_file: Option<String>,
+    /// Only the replica holding the lease on this file changes DNS records if set
+    pub leader_lease_file: Option<String>,
+    pub cf_token: Option<String>,
+    pub prometheus_enabled: bool,
+    pub prometheus_host: IpAddr,
+    pub prometheus_port: u16,
+    pub admin_enabled: bool,
+    pub admin_token: Option<String>,
+}
 
 impl Config {
-    pub fn read_service_targets(data: &str) -> Vec<ServiceTarget> {
+    pub fn load(config_path: Option<String>) -> Config {
+        let file = config_path
+            .map(|path| Config::read_config_file(path.as_str()))
+            .unwrap_or_default();
+        let cloudflare = file.cloudflare.unwrap_or_default();
+        let prometheus = file.prometheus.unwrap_or_default();
+        let admin = file.admin.unwrap_or_default();
+        let tls = file.tls.unwrap_or_default();
+
+        let service_targets = match env::var("SERVICE_TARGETS") {
+            Ok(service_data) => { Config::read_service_targets(service_data.as_str(), &tls) }
+            Err(_) => {
+                Config::parse_service_targets(file.targets
+                    .expect("Please provide `targets` in the config file or `SERVICE_TARGETS` in env!"), &tls)
+            }
+        };
+
+        // a token reference from the config file is only resolved if env does not provide one
+        let token_env = cloudflare.token_env;
+        let token_file = cloudflare.token_file;
+        let cf_token = env::var("CF_TOKEN").ok()
+            .or(cloudflare.token)
+            .or_else(|| token_env.map(|name| {
+                env::var(name.as_str())
+                    .unwrap_or_else(|_| panic!("The env variable `{}` referenced by token_env is not set", name))
+            }))
+            .or_else(|| token_file.map(|path| {
+                fs::read_to_string(path.as_str())
+                    .unwrap_or_else(|_| panic!("Couldn't read the token_file `{}`", path))
+                    .trim()
+                    .to_owned()
+            }));
+
+        Config {
+            service_targets,
+            check_interval: Duration::from_secs(Config::env_or("CHECK_INTERVAL", file.check_interval, 30)),
+            reconcile_every: Config::env_or("RECONCILE_EVERY", file.reconcile_every, 10),
+            dns_provider: env::var("DNS_PROVIDER").ok()
+                .or(file.dns_provider)
+                .unwrap_or("cloudflare".to_owned()),
+            dry_run: Config::env_or("DRY_RUN", file.dry_run, false),
+            state_file: env::var("STATE_FILE").ok().or(file.state_file),
+            leader_lease_file: env::var("LEADER_LEASE_FILE").ok().or(file.leader_lease_file),
+            cf_token,
+            prometheus_enabled: Config::env_or("PROMETHEUS_ENABLED", prometheus.enabled, false),
+            prometheus_host: Config::env_or("PROMETHEUS_HOST", prometheus.host, IpAddr::from([0, 0, 0, 0])),
+            prometheus_port: Config::env_or("PROMETHEUS_PORT", prometheus.port, 8080),
+            admin_enabled: Config::env_or("ADMIN_ENABLED", admin.enabled, false),
+            admin_token: env::var("ADMIN_TOKEN").ok().or(admin.token),
+        }
+    }
+
+    /// Returns the path passed as `--config <path>` or `--config=<path>`
+    pub fn config_path_from_args() -> Option<String> {
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                return Some(args.next().expect("--config expects a path"));
+            }
+            if let Some(path) = arg.strip_prefix("--config=") {
+                return Some(path.to_owned());
+            }
+        }
+        None
+    }
+
+    fn read_config_file(path: &str) -> SerializedConfig {
+        let data = fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("Couldn't read the config file `{}`", path));
+        let extension = Path::new(path).extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        match extension.as_str() {
+            "toml" => { toml::from_str(data.as_str()).expect("Invalid toml config file") }
+            "yaml" | "yml" => { serde_yaml::from_str(data.as_str()).expect("Invalid yaml config file") }
+            "json" => { serde_json::from_str(data.as_str()).expect("Invalid json config file") }
+            _ => {
+                panic!("Invalid config file extension, please use .toml, .yaml, .yml or .json")
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, file_value: Option<T>, default: T) -> T {
+        match env::var(name) {
+            Ok(value) => {
+                value.parse()
+                    .unwrap_or_else(|_| panic!("Invalid value for `{}` in env", name))
+            }
+            Err(_) => { file_value.unwrap_or(default) }
+        }
+    }
+
+    pub fn read_service_targets(data: &str, tls: &SerializedTlsConfig) -> Vec<ServiceTarget> {
         let parsed: Vec<SerializedServiceTarget> = serde_json::from_str(data)
             .expect("Invalid service_targets json");
-        parsed.iter()
+        Config::parse_service_targets(parsed, tls)
+    }
+
+    fn parse_service_targets(parsed: Vec<SerializedServiceTarget>, tls: &SerializedTlsConfig) -> Vec<ServiceTarget> {
+        let targets = parsed.into_iter()
             .map(|ser| {
-                let ser = ser.clone();
+                let (mode, zone, dns) = match ser.mode.unwrap_or("dns".to_owned()).to_lowercase().as_str() {
+                    "dns" => {
+                        (
+                            TargetMode::DnsRecord,
+                            ser.cf_zone.expect("DNS targets expect a cf_zone field"),
+                            ser.cf_dns.expect("DNS targets expect a cf_dns field"),
+                        )
+                    }
+                    "lb_pool" => {
+                        (
+                            TargetMode::LoadBalancerPool,
+                            ser.cf_account.expect("Load balancer pool targets expect a cf_account field"),
+                            ser.cf_pool.expect("Load balancer pool targets expect a cf_pool field"),
+                        )
+                    }
+                    _ => {
+                        panic!("Invalid target mode provided, please use dns or lb_pool")
+                    }
+                };
+                // the name the target is balanced for, unknown for load balancer pools
+                let hostname = match mode {
+                    TargetMode::DnsRecord => { Some(dns.clone()) }
+                    TargetMode::LoadBalancerPool => { None }
+                };
+                let checks = ser.check.into_iter()
+                    .chain(ser.checks.unwrap_or_default())
+                    .map(|check| Config::parse_service_uri(check, hostname.clone(), tls))
+                    .collect::<Vec<ServiceUri>>();
+                if checks.is_empty() {
+                    panic!("Targets expect a check or checks field");
+                }
+                let combinator = match ser.combinator {
+                    None => { CheckCombinator::All }
+                    Some(SerializedCombinator::AtLeast(required)) => {
+                        if required == 0 || required > checks.len() {
+                            panic!("Invalid combinator {}, the target has {} checks", required, checks.len());
+                        }
+                        CheckCombinator::AtLeast(required)
+                    }
+                    Some(SerializedCombinator::Name(name)) => {
+                        match name.to_lowercase().as_str() {
+                            "all" => { CheckCombinator::All }
+                            "any" => { CheckCombinator::Any }
+                            _ => {
+                                panic!("Invalid combinator {}, please use all, any or the amount of checks which have to pass", name)
+                            }
+                        }
+                    }
+                };
                 ServiceTarget {
-                    target: Ipv4Addr::from_str(ser.ip.as_str()).expect("Invalid IP"),
-                    check: Config::parse_service_uri(ser.check),
-                    zone: ser.cf_zone,
-                    dns: ser.cf_dns,
+                    id: ser.id.unwrap_or(format!("{}-{}", dns, ser.ip)),
+                    target: IpAddr::from_str(ser.ip.as_str()).expect("Invalid IP"),
+                    checks,
+                    combinator,
+                    mode,
+                    zone,
+                    dns,
                     response_threshold_ms: ser.response_threshold_ms,
+                    interval: ser.interval.map(|interval| Duration::from_secs(interval.max(1))),
+                    jitter: Duration::from_millis(ser.jitter_ms.unwrap_or(0)),
+                    rise: ser.rise.unwrap_or(DEFAULT_RISE).max(1),
+                    fall: ser.fall.unwrap_or(DEFAULT_FALL).max(1),
                 }
             })
-            .collect()
+            .collect::<Vec<ServiceTarget>>();
+        for (index, target) in targets.iter().enumerate() {
+            if targets[..index].iter().any(|other| other.id.eq(&target.id)) {
+                panic!("Duplicate target id {}", target.id);
+            }
+        }
+        targets
     }
 
-    fn parse_service_uri(ser: SerializedServiceUri) -> ServiceUri {
+    fn parse_service_uri(ser: SerializedServiceUri, hostname: Option<String>, tls: &SerializedTlsConfig) -> ServiceUri {
         match ser.r#type.to_lowercase().as_str() {
             "icmp" => {
-                ServiceUri::Icmp
+                ServiceUri::Icmp(IcmpCheck {
+                    count: ser.count.unwrap_or(DEFAULT_ICMP_COUNT).max(1),
+                    max_loss_percent: ser.max_loss_percent.unwrap_or(DEFAULT_ICMP_MAX_LOSS_PERCENT),
+                    max_rtt_ms: ser.max_rtt_ms,
+                })
             }
             "tcpprobe" => {
-                ServiceUri::TcpProbe(ser.port.expect("TcpProbe expects a port field"))
+                ServiceUri::TcpProbe(TcpCheck {
+                    port: ser.port.expect("TcpProbe expects a port field"),
+                    send: ser.send,
+                    expect: ser.expect,
+                })
             }
             "http" => {
-                ServiceUri::Http(
-                    ser.port.expect("HTTP expects a port field"),
-                    Method::from_str(ser.method.unwrap_or("GET".to_owned()).as_str()).expect("Invalid HTTP method"),
-                    ser.route.unwrap_or("/".to_owned()),
-                )
+                ServiceUri::Http(Config::parse_http_check(ser, hostname, None, TlsConfig::default()))
             }
             "https" => {
-                ServiceUri::HttpSecure(
-                    ser.port.expect("HTTP expects a port field"),
-                    Method::from_str(ser.method.unwrap_or("GET".to_owned()).as_str()).expect("Invalid HTTP method"),
-                    ser.route.unwrap_or("/".to_owned()),
-                )
+                let tls = Config::parse_tls_config(&ser, tls);
+                // skipping verification is meant for targets whose certificate would fail the certificate check
+                let certificate = if tls.insecure_skip_verify {
+                    None
+                } else {
+                    Some(CertificateCheck {
+                        warn_days: ser.cert_warn_days.unwrap_or(DEFAULT_CERT_WARN_DAYS),
+                        fail_days: ser.cert_fail_days.unwrap_or(0),
+                        hostname: ser.cert_hostname.clone().or(ser.host.clone()).or(hostname.clone()),
+                    })
+                };
+                ServiceUri::HttpSecure(Config::parse_http_check(ser, hostname, certificate, tls))
+            }
+            "grpc" => {
+                ServiceUri::Grpc(GrpcCheck {
+                    port: ser.port.expect("Grpc expects a port field"),
+                    service: ser.service.unwrap_or_default(),
+                    tls: ser.tls.unwrap_or(false),
+                    hostname: ser.cert_hostname.or(hostname),
+                })
+            }
+            "dns" => {
+                let query_name = ser.query_name.expect("Dns expects a query_name field");
+                let record_type = ser.record_type.unwrap_or_else(|| "A".to_string());
+                let protocol = match ser.protocol.unwrap_or_else(|| "udp".to_string()).to_lowercase().as_str() {
+                    "udp" => { DnsProtocol::Udp }
+                    "tcp" => { DnsProtocol::Tcp }
+                    protocol => {
+                        panic!("Invalid dns protocol {}, please use udp or tcp", protocol)
+                    }
+                };
+                ServiceUri::Dns(DnsCheck {
+                    port: ser.port.unwrap_or(DEFAULT_DNS_PORT),
+                    name: Name::from_str(query_name.as_str())
+                        .unwrap_or_else(|_| panic!("Invalid query_name {}", query_name)),
+                    record_type: RecordType::from_str(record_type.to_uppercase().as_str())
+                        .unwrap_or_else(|_| panic!("Invalid record_type {}", record_type)),
+                    protocol,
+                    expected_answer: ser.expected_answer,
+                })
+            }
+            "exec" => {
+                ServiceUri::Exec(ExecCheck {
+                    command: ser.command.expect("Exec expects a command field"),
+                    args: ser.args.unwrap_or_default(),
+                    port: ser.port,
+                })
             }
             _ => {
-                panic!("Invalid service type provided, please use Icmp, TcpProbe, Http or Https")
+                panic!("Invalid service type provided, please use Icmp, TcpProbe, Http, Https, Grpc, Dns or Exec")
+            }
+        }
+    }
+
+    fn parse_http_check(ser: SerializedServiceUri, hostname: Option<String>, certificate: Option<CertificateCheck>, tls: TlsConfig) -> HttpCheck {
+        let body_match = match (ser.body_contains, ser.body_regex) {
+            (Some(_), Some(_)) => {
+                panic!("Only one of body_contains and body_regex may be provided")
+            }
+            (Some(needle), None) => { Some(BodyMatch::Contains(needle)) }
+            (None, Some(regex)) => {
+                Some(BodyMatch::Regex(Regex::new(regex.as_str()).expect("Invalid body_regex")))
+            }
+            (None, None) => { None }
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in ser.headers.unwrap_or_default() {
+            let value = Config::resolve_value(value);
+            headers.insert(
+                HeaderName::from_str(name.as_str()).unwrap_or_else(|_| panic!("Invalid header name {}", name)),
+                HeaderValue::from_str(value.as_str()).unwrap_or_else(|_| panic!("Invalid value of header {}", name)),
+            );
+        }
+        if let Some(content_type) = ser.content_type {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type.as_str()).expect("Invalid content_type"));
+        }
+        HttpCheck {
+            port: ser.port.expect("HTTP expects a port field"),
+            method: Method::from_str(ser.method.unwrap_or("GET".to_owned()).as_str()).expect("Invalid HTTP method"),
+            route: ser.route.unwrap_or("/".to_owned()),
+            headers,
+            body: ser.body,
+            expected_status: ser.expected_status.unwrap_or_default().iter()
+                .map(Config::parse_status_code)
+                .collect(),
+            body_match,
+            host: ser.host.or(hostname),
+            certificate,
+            tls,
+        }
+    }
+
+    /// Reads the files referenced by the check, falling back to the global TLS settings
+    fn parse_tls_config(ser: &SerializedServiceUri, defaults: &SerializedTlsConfig) -> TlsConfig {
+        let ca_certificates = match ser.ca_bundle.as_ref().or(defaults.ca_bundle.as_ref()) {
+            Some(path) => {
+                let bundle = fs::read_to_string(path)
+                    .unwrap_or_else(|_| panic!("Couldn't read the ca_bundle `{}`", path));
+                let certificates = Config::split_pem_certificates(bundle.as_str());
+                if certificates.is_empty() {
+                    panic!("The ca_bundle `{}` does not contain any certificate", path);
+                }
+                for certificate in &certificates {
+                    reqwest::Certificate::from_pem(certificate)
+                        .unwrap_or_else(|_| panic!("Invalid certificate in the ca_bundle `{}`", path));
+                }
+                certificates
+            }
+            None => { Vec::new() }
+        };
+        let client_cert = ser.client_cert.as_ref().or(defaults.client_cert.as_ref());
+        let client_key = ser.client_key.as_ref().or(defaults.client_key.as_ref());
+        let client_identity = match (client_cert, client_key) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = fs::read(cert_path)
+                    .unwrap_or_else(|_| panic!("Couldn't read the client_cert `{}`", cert_path));
+                let key = fs::read(key_path)
+                    .unwrap_or_else(|_| panic!("Couldn't read the client_key `{}`", key_path));
+                reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                    .expect("Invalid client_cert or client_key, the key has to be PKCS#8 PEM encoded");
+                Some((cert, key))
+            }
+            (None, None) => { None }
+            _ => {
+                panic!("client_cert and client_key have to be provided together")
+            }
+        };
+        TlsConfig {
+            ca_certificates,
+            insecure_skip_verify: ser.insecure_skip_verify.or(defaults.insecure_skip_verify).unwrap_or(false),
+            client_identity,
+        }
+    }
+
+    /// Splits a PEM bundle into its single certificates
+    fn split_pem_certificates(bundle: &str) -> Vec<Vec<u8>> {
+        const END_MARKER: &str = "-----END CERTIFICATE-----";
+        bundle.split_inclusive(END_MARKER)
+            .filter(|block| block.contains(END_MARKER))
+            .map(|block| block.trim().as_bytes().to_vec())
+            .collect()
+    }
+
+    /// Resolves `env:NAME` and `file:PATH` references, other values are used as they are
+    fn resolve_value(value: String) -> String {
+        if let Some(name) = value.strip_prefix("env:") {
+            env::var(name)
+                .unwrap_or_else(|_| panic!("The env variable `{}` referenced by a header is not set", name))
+        } else if let Some(path) = value.strip_prefix("file:") {
+            fs::read_to_string(path)
+                .unwrap_or_else(|_| panic!("Couldn't read the file `{}` referenced by a header", path))
+                .trim()
+                .to_owned()
+        } else {
+            value
+        }
+    }
+
+    fn parse_status_code(ser: &SerializedStatusCode) -> StatusRange {
+        match ser {
+            SerializedStatusCode::Code(code) => {
+                StatusRange { from: *code, to: *code }
+            }
+            SerializedStatusCode::Range(range) => {
+                let mut split = range.splitn(2, '-')
+                    .map(|code| u16::from_str(code.trim()).expect("Invalid status code in expected_status"));
+                let from = split.next().expect("Invalid status code in expected_status");
+                let to = split.next().unwrap_or(from);
+                if from > to {
+                    panic!("Invalid status code range {} in expected_status", range);
+                }
+                StatusRange { from, to }
             }
         }
     }
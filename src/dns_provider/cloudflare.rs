@@ -0,0 +1,110 @@
+use crate::dns_provider::{DnsProvider, ProviderError};
+use crate::metrics::CLOUDFLARE_REQUEST_COUNTER;
+use async_trait::async_trait;
+use cloudflare::endpoints::dns::{ListDnsRecordsParams, ListDnsRecords, DnsContent, DnsRecord, CreateDnsRecord, CreateDnsRecordParams, DeleteDnsRecord};
+use cloudflare::framework::async_api::Client;
+use cloudflare::framework::response::ApiSuccess;
+use std::net::IpAddr;
+
+pub struct CloudflareProvider {
+    client: Client,
+}
+
+impl CloudflareProvider {
+    pub fn new(client: Client) -> CloudflareProvider {
+        CloudflareProvider {
+            client
+        }
+    }
+
+    async fn list_dns(&self, zone: &str, name: &str) -> Result<Vec<DnsRecord>, ProviderError> {
+        CLOUDFLARE_REQUEST_COUNTER
+            .with_label_values(&["list_dns"])
+            .inc();
+        let response: ApiSuccess<Vec<DnsRecord>> = self.client.request_handle(&ListDnsRecords {
+            zone_identifier: zone,
+            params: ListDnsRecordsParams {
+                record_type: None,
+                name: Some(name.to_owned()),
+                page: None,
+                per_page: Some(100),
+                order: None,
+                direction: None,
+                search_match: None,
+            },
+        }).await.map_err(|error| ProviderError::new(format!("Error with CF Api: {}", error)))?;
+        if !response.errors.is_empty() {
+            let errors = response.errors.iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>();
+            return Err(ProviderError::new(format!("CF Api Error: {}", errors.join(", "))));
+        }
+        Ok(response.result)
+    }
+
+    /// Extracts the address of an A or AAAA record, other record types are not managed by us
+    fn dns_content_ip(content: &DnsContent) -> Option<IpAddr> {
+        match content {
+            DnsContent::A { content } => { Some(IpAddr::V4(*content)) }
+            DnsContent::AAAA { content } => { Some(IpAddr::V6(*content)) }
+            _ => { None }
+        }
+    }
+
+    fn dns_content_for(ip: IpAddr) -> DnsContent {
+        match ip {
+            IpAddr::V4(content) => { DnsContent::A { content } }
+            IpAddr::V6(content) => { DnsContent::AAAA { content } }
+        }
+    }
+}
+
+#[async_trait]
+impl DnsProvider for CloudflareProvider {
+    async fn list_records(&self, zone: &str, name: &str) -> Result<Vec<IpAddr>, ProviderError> {
+        let records = self.list_dns(zone, name).await?;
+        Ok(records.iter()
+            .filter_map(|dns| CloudflareProvider::dns_content_ip(&dns.content))
+            .collect())
+    }
+
+    async fn add_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<(), ProviderError> {
+        CLOUDFLARE_REQUEST_COUNTER
+            .with_label_values(&["create_dns"])
+            .inc();
+        self.client.request_handle(&CreateDnsRecord {
+            zone_identifier: zone,
+            params: CreateDnsRecordParams {
+                ttl: None,
+                priority: None,
+                proxied: Some(true),
+                name,
+                content: CloudflareProvider::dns_content_for(ip),
+            },
+        }).await.map_err(|error| ProviderError::new(format!("Error with CF Api: {}", error)))?;
+        Ok(())
+    }
+
+    async fn remove_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<bool, ProviderError> {
+        let records = self.list_dns(zone, name).await?;
+        let opt_entry = records.iter()
+            .find(|dns| {
+                CloudflareProvider::dns_content_ip(&dns.content)
+                    .map(|content| content.eq(&ip))
+                    .unwrap_or(false)
+            });
+        match opt_entry {
+            Some(entry) => {
+                CLOUDFLARE_REQUEST_COUNTER
+                    .with_label_values(&["delete_dns"])
+                    .inc();
+                self.client.request_handle(&DeleteDnsRecord {
+                    zone_identifier: zone,
+                    identifier: entry.id.as_str(),
+                }).await.map_err(|error| ProviderError::new(format!("Error with CF Api: {}", error)))?;
+                Ok(true)
+            }
+            None => { Ok(false) }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::dns_provider::{DnsProvider, ProviderError};
+use crate::metrics::CLOUDFLARE_REQUEST_COUNTER;
+use async_trait::async_trait;
+use cloudflare::framework::async_api::Client;
+use cloudflare::framework::endpoint::{Endpoint, Method};
+use cloudflare::framework::response::ApiResult;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Fields of an origin reported by the API which are rejected when patching the pool
+const READ_ONLY_ORIGIN_FIELDS: [&str; 3] = ["healthy", "disabled_at", "failure_reason"];
+
+/// Enables and disables origins of a Cloudflare Load Balancing pool instead of managing DNS records.
+/// The zone is the account identifier and the name is the pool identifier. Origins have to exist in
+/// the pool already, they are matched by their address.
+pub struct CloudflarePoolProvider {
+    client: Client,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Pool {
+    origins: Vec<Origin>,
+}
+
+impl ApiResult for Pool {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Origin {
+    address: String,
+    enabled: bool,
+    /// name, weight, port, headers, virtual_network_id etc. are passed through untouched when patching the pool
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Origin {
+    fn ip(&self) -> Option<IpAddr> {
+        IpAddr::from_str(self.address.as_str()).ok()
+    }
+
+    fn remove_read_only_fields(&mut self) {
+        for field in READ_ONLY_ORIGIN_FIELDS.iter() {
+            self.other.remove(*field);
+        }
+    }
+}
+
+struct PoolDetails<'a> {
+    account_identifier: &'a str,
+    identifier: &'a str,
+}
+
+impl<'a> Endpoint<Pool> for PoolDetails<'a> {
+    fn method(&self) -> Method {
+        Method::Get
+    }
+    fn path(&self) -> String {
+        format!("accounts/{}/load_balancers/pools/{}", self.account_identifier, self.identifier)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PatchPoolParams {
+    origins: Vec<Origin>,
+}
+
+struct PatchPool<'a> {
+    account_identifier: &'a str,
+    identifier: &'a str,
+    params: PatchPoolParams,
+}
+
+impl<'a> Endpoint<Pool, (), PatchPoolParams> for PatchPool<'a> {
+    fn method(&self) -> Method {
+        Method::Patch
+    }
+    fn path(&self) -> String {
+        format!("accounts/{}/load_balancers/pools/{}", self.account_identifier, self.identifier)
+    }
+    fn body(&self) -> Option<PatchPoolParams> {
+        Some(self.params.clone())
+    }
+}
+
+impl CloudflarePoolProvider {
+    pub fn new(client: Client) -> CloudflarePoolProvider {
+        CloudflarePoolProvider {
+            client
+        }
+    }
+
+    async fn pool_details(&self, account: &str, pool: &str) -> Result<Pool, ProviderError> {
+        CLOUDFLARE_REQUEST_COUNTER
+            .with_label_values(&["pool_details"])
+            .inc();
+        let response = self.client.request_handle(&PoolDetails {
+            account_identifier: account,
+            identifier: pool,
+        }).await.map_err(|error| ProviderError::new(format!("Error with CF Api: {}", error)))?;
+        if !response.errors.is_empty() {
+            let errors = response.errors.iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>();
+            return Err(ProviderError::new(format!("CF Api Error: {}", errors.join(", "))));
+        }
+        Ok(response.result)
+    }
+
+    /// Sets the enabled flag of the origin with the given address, returns false if it was already set
+    async fn set_origin_enabled(&self, account: &str, pool: &str, ip: IpAddr, enabled: bool) -> Result<bool, ProviderError> {
+        let mut origins = self.pool_details(account, pool).await?.origins;
+        let origin = origins.iter_mut()
+            .find(|origin| origin.ip() == Some(ip))
+            .ok_or_else(|| ProviderError::new(format!("Pool {} has no origin with address {}", pool, ip)))?;
+        if origin.enabled == enabled {
+            return Ok(false);
+        }
+        origin.enabled = enabled;
+        // the whole list of origins is replaced, so the others are sent back as they are
+        for origin in origins.iter_mut() {
+            origin.remove_read_only_fields();
+        }
+
+        CLOUDFLARE_REQUEST_COUNTER
+            .with_label_values(&["patch_pool"])
+            .inc();
+        self.client.request_handle(&PatchPool {
+            account_identifier: account,
+            identifier: pool,
+            params: PatchPoolParams { origins },
+        }).await.map_err(|error| ProviderError::new(format!("Error with CF Api: {}", error)))?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl DnsProvider for CloudflarePoolProvider {
+    async fn list_records(&self, account: &str, pool: &str) -> Result<Vec<IpAddr>, ProviderError> {
+        let pool = self.pool_details(account, pool).await?;
+        Ok(pool.origins.iter()
+            .filter(|origin| origin.enabled)
+            .filter_map(|origin| origin.ip())
+            .collect())
+    }
+
+    async fn add_record(&self, account: &str, pool: &str, ip: IpAddr) -> Result<(), ProviderError> {
+        self.set_origin_enabled(account, pool, ip, true).await?;
+        Ok(())
+    }
+
+    async fn remove_record(&self, account: &str, pool: &str, ip: IpAddr) -> Result<bool, ProviderError> {
+        self.set_origin_enabled(account, pool, ip, false).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn patched_origins_keep_editable_fields() {
+        let mut origin: Origin = serde_json::from_value(json!({
+            "name": "tunnel",
+            "address": "10.0.0.1",
+            "enabled": true,
+            "weight": 0.5,
+            "port": 8443,
+            "virtual_network_id": "a5624d4e-044a-4ff0-b3e1-e2465353d4b4",
+            "healthy": true,
+            "disabled_at": "2021-04-01T00:00:00Z",
+            "failure_reason": "No failures",
+        })).unwrap();
+        origin.enabled = false;
+        origin.remove_read_only_fields();
+
+        assert_eq!(serde_json::to_value(&origin).unwrap(), json!({
+            "name": "tunnel",
+            "address": "10.0.0.1",
+            "enabled": false,
+            "weight": 0.5,
+            "port": 8443,
+            "virtual_network_id": "a5624d4e-044a-4ff0-b3e1-e2465353d4b4",
+        }));
+    }
+}
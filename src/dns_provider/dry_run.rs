@@ -0,0 +1,64 @@
+use crate::dns_provider::{DnsProvider, ProviderError};
+use crate::metrics::DRY_RUN_CHANGES;
+use async_trait::async_trait;
+use log::info;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Reads records from the wrapped provider, but only logs and counts the changes it would have made.
+/// The changes are remembered and applied to the listed records, so every change is only made once
+pub struct DryRunProvider {
+    inner: Arc<dyn DnsProvider>,
+    /// Whether the record would exist after the changes, by zone, name and address
+    changes: Mutex<HashMap<(String, String, IpAddr), bool>>,
+}
+
+impl DryRunProvider {
+    pub fn new(inner: Arc<dyn DnsProvider>) -> DryRunProvider {
+        DryRunProvider {
+            inner,
+            changes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record_change(&self, zone: &str, name: &str, ip: IpAddr, exists: bool) {
+        self.changes.lock().unwrap().insert((zone.to_owned(), name.to_owned(), ip), exists);
+    }
+}
+
+#[async_trait]
+impl DnsProvider for DryRunProvider {
+    async fn list_records(&self, zone: &str, name: &str) -> Result<Vec<IpAddr>, ProviderError> {
+        let mut records = self.inner.list_records(zone, name).await?;
+        let changes = self.changes.lock().unwrap();
+        for ((change_zone, change_name, ip), exists) in changes.iter() {
+            if change_zone != zone || change_name != name {
+                continue;
+            }
+            if *exists && !records.contains(ip) {
+                records.push(*ip);
+            } else if !*exists {
+                records.retain(|record| record != ip);
+            }
+        }
+        Ok(records)
+    }
+
+    async fn add_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<(), ProviderError> {
+        self.record_change(zone, name, ip, true);
+        DRY_RUN_CHANGES.with_label_values(&["add", name]).inc();
+        info!("Dry run: would have added {} -> {}", name, ip);
+        Ok(())
+    }
+
+    async fn remove_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<bool, ProviderError> {
+        let exists = self.list_records(zone, name).await?.contains(&ip);
+        if exists {
+            self.record_change(zone, name, ip, false);
+            DRY_RUN_CHANGES.with_label_values(&["remove", name]).inc();
+            info!("Dry run: would have removed {} -> {}", name, ip);
+        }
+        Ok(exists)
+    }
+}
@@ -0,0 +1,47 @@
+use crate::dns_provider::{DnsProvider, ProviderError};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Keeps all records in memory, useful to try out target configurations without touching any zone
+#[derive(Default)]
+pub struct MemoryProvider {
+    records: Mutex<HashMap<(String, String), Vec<IpAddr>>>,
+}
+
+impl MemoryProvider {
+    pub fn new() -> MemoryProvider {
+        MemoryProvider::default()
+    }
+}
+
+#[async_trait]
+impl DnsProvider for MemoryProvider {
+    async fn list_records(&self, zone: &str, name: &str) -> Result<Vec<IpAddr>, ProviderError> {
+        let records = self.records.lock().unwrap();
+        Ok(records.get(&(zone.to_owned(), name.to_owned()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn add_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<(), ProviderError> {
+        let mut records = self.records.lock().unwrap();
+        records.entry((zone.to_owned(), name.to_owned()))
+            .or_default()
+            .push(ip);
+        Ok(())
+    }
+
+    async fn remove_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<bool, ProviderError> {
+        let mut records = self.records.lock().unwrap();
+        match records.get_mut(&(zone.to_owned(), name.to_owned())) {
+            Some(ips) => {
+                let before = ips.len();
+                ips.retain(|other| !ip.eq(other));
+                Ok(before != ips.len())
+            }
+            None => { Ok(false) }
+        }
+    }
+}
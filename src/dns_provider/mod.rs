@@ -0,0 +1,48 @@
+mod cloudflare;
+mod cloudflare_pool;
+mod dry_run;
+mod memory;
+
+pub use self::cloudflare::CloudflareProvider;
+pub use self::cloudflare_pool::CloudflarePoolProvider;
+pub use self::dry_run::DryRunProvider;
+pub use self::memory::MemoryProvider;
+
+use async_trait::async_trait;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Backend which manages the A and AAAA records of the balanced DNS names,
+/// or the equivalent membership of an address like the origins of a load balancer pool
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Lists the addresses of all A and AAAA records of the given name
+    async fn list_records(&self, zone: &str, name: &str) -> Result<Vec<IpAddr>, ProviderError>;
+
+    /// Creates an A or AAAA record, depending on the address family
+    async fn add_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<(), ProviderError>;
+
+    /// Deletes the record pointing to the given address, returns false if no such record existed
+    async fn remove_record(&self, zone: &str, name: &str, ip: IpAddr) -> Result<bool, ProviderError>;
+}
+
+#[derive(Debug)]
+pub struct ProviderError {
+    message: String,
+}
+
+impl ProviderError {
+    pub fn new<S: Into<String>>(message: S) -> ProviderError {
+        ProviderError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message.as_str())
+    }
+}
+
+impl std::error::Error for ProviderError {}
@@ -1,230 +1,677 @@
-use crate::models::{ServiceTarget, ServiceUri};
-use crate::metrics::{CLOUDFLARE_REQUEST_COUNTER, TARGETS_AVAILABLE, HEALTHCHECK_REQUEST_TIME, TARGETS_STATUS};
-use cloudflare::framework::async_api::Client;
+use crate::models::{ServiceTarget, ServiceUri, HttpCheck, IcmpCheck, TcpCheck, GrpcCheck, DnsCheck, DnsProtocol, ExecCheck, TlsConfig, TargetMode, TargetKey, SerializedState, SerializedTargetState};
+use crate::admin::{LeaderBoard, LeaderStatus, StatusBoard, TargetStatus};
+use crate::certificate::check_certificate;
+use crate::dns_provider::DnsProvider;
+use crate::state::StateFile;
+use crate::leader_election::LeaderElection;
+use crate::metrics::{LEADER, CHECK_STATUS, DRIFT_CORRECTIONS, ICMP_LOSS, ICMP_RTT, TCP_CONNECT_TIME, TLS_CERTIFICATE_EXPIRY_DAYS, TARGETS_AVAILABLE, HEALTHCHECK_REQUEST_TIME, TARGETS_STATUS, TARGETS_CONSECUTIVE_SUCCESSES, TARGETS_CONSECUTIVE_FAILURES};
 use tokio::task::JoinHandle;
 use tokio::task::spawn_blocking;
 use tokio::time::Duration;
 use log::{debug, info, warn};
-use warp::http::Method;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 #[cfg(not(target_env = "msvc"))]
 use oping::Ping;
-use std::ops::Sub;
-use std::net::{IpAddr, SocketAddr, Ipv4Addr, TcpStream};
-use cloudflare::endpoints::dns::{ListDnsRecordsParams, ListDnsRecords, DnsContent, DnsRecord, CreateDnsRecord, CreateDnsRecordParams, DeleteDnsRecord};
-use cloudflare::framework::response::{ApiSuccess, ApiResponse};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio::process::Command as ProcessCommand;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use rand::Rng;
+use reqwest::{Certificate, Identity};
+use tokio_native_tls::TlsConnector;
+use std::error::Error;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::pb::health_check_response::ServingStatus;
+use trust_dns_client::client::{AsyncClient, ClientHandle};
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::proto::iocompat::AsyncIoTokioAsStd;
+use trust_dns_client::rr::DNSClass;
+use trust_dns_client::tcp::TcpClientStream;
+use trust_dns_client::udp::UdpClientStream;
+
+/// Upper bound of data read while waiting for the expected response of a TCP check
+const MAX_TCP_RESPONSE_BYTES: usize = 64 * 1024;
+/// Interval in which changed check counters are written to the state file, transitions and drains are written at once
+const STATE_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// Interval in which the leadership is renewed, or acquired by followers
+const LEADER_ELECTION_INTERVAL: Duration = Duration::from_secs(5);
 
 pub(crate) struct HealthChecker {
-    cloudflare_client: Client,
+    dns_provider: Arc<dyn DnsProvider>,
+    pool_provider: Arc<dyn DnsProvider>,
     http_client: reqwest::Client,
+    /// Clients of the HTTP(S) checks of every target by check index, None for other checks or if it couldn't be built
+    http_clients: HashMap<TargetKey, Vec<Option<HttpCheckClients>>>,
     targets: Vec<ServiceTarget>,
-    unavailable: Vec<Ipv4Addr>,
+    /// Targets whose record was removed or found missing
+    unavailable: Vec<TargetKey>,
+    health: HashMap<TargetKey, TargetHealth>,
+    drained: Vec<String>,
+    status: StatusBoard,
+    state_file: Option<StateFile>,
+    /// Last state written to the state file
+    saved_state: SerializedState,
+    state_flushed: Instant,
+    /// Without an election, this replica is always the leader
+    leader_election: Option<Arc<dyn LeaderElection>>,
+    /// Only the leader changes DNS records, followers just keep checking
+    leading: bool,
+    leadership: LeaderBoard,
+}
+
+/// Instructions for the running health checker
+#[derive(Debug)]
+pub enum Command {
+    /// Replaces the checked targets
+    Reload(Vec<ServiceTarget>),
+    /// Removes the target with the given id from DNS, regardless of its health
+    Drain(String),
+    /// Adds the target with the given id back to DNS and resumes checking it
+    Undrain(String),
+}
+
+/// Clients of a single HTTP(S) check
+#[derive(Clone)]
+struct HttpCheckClients {
+    client: reqwest::Client,
+    /// Connects without verifying the certificate, so it can be inspected even if the verification fails
+    certificate_inspector: Option<TlsConnector>,
+}
+
+/// Result of a single scheduled check of a target
+struct CheckResult {
+    target: ServiceTarget,
+    up: bool,
+    /// None if the check could not be completed
+    duration: Option<Duration>,
+}
+
+/// Check results of a target, the consecutive ones are compared against its rise and fall thresholds
+#[derive(Debug, Default, Clone)]
+struct TargetHealth {
+    successes: u32,
+    failures: u32,
+    /// None until either threshold has been reached
+    healthy: Option<bool>,
+    last_result: Option<bool>,
+    last_duration: Option<Duration>,
+    last_transition: Option<SystemTime>,
 }
 
 impl HealthChecker {
-    pub fn new(cloudflare_client: Client, targets: Vec<ServiceTarget>) -> HealthChecker {
-        let http_client = reqwest::ClientBuilder::new()
-            .user_agent("rusty-cloudflare-dns-balancer")
+    pub fn new(dns_provider: Arc<dyn DnsProvider>, pool_provider: Arc<dyn DnsProvider>, targets: Vec<ServiceTarget>,
+               state_file: Option<StateFile>, leader_election: Option<Arc<dyn LeaderElection>>) -> HealthChecker {
+        let http_client = HealthChecker::http_client_builder()
             .build().unwrap();
-        HealthChecker {
-            cloudflare_client,
+        let mut checker = HealthChecker {
+            dns_provider,
+            pool_provider,
             http_client,
+            http_clients: HashMap::new(),
             targets,
             unavailable: Vec::new(),
-        }
+            health: HashMap::new(),
+            drained: Vec::new(),
+            status: Arc::new(RwLock::new(Vec::new())),
+            state_file,
+            saved_state: SerializedState::default(),
+            state_flushed: Instant::now(),
+            leading: leader_election.is_none(),
+            leadership: Arc::new(RwLock::new(LeaderStatus { leading: leader_election.is_none(), leader: None })),
+            leader_election,
+        };
+        checker.build_http_clients();
+        checker
     }
 
-    pub fn run(mut self, interval: Duration) -> JoinHandle<()> {
-        let http_client = self.http_client.clone();
+    /// The status of all targets, updated after every health check
+    pub fn status(&self) -> StatusBoard {
+        self.status.clone()
+    }
+
+    /// Whether this replica is the leader, updated after every election
+    pub fn leadership(&self) -> LeaderBoard {
+        self.leadership.clone()
+    }
+
+    /// Checks the targets, the records are reconciled every `reconcile_interval` if given
+    pub fn run(mut self, interval: Duration, reconcile_interval: Option<Duration>, mut commands: Receiver<Command>) -> JoinHandle<()> {
         tokio::spawn(async move {
             // Init
-            let mut dns_regions = Vec::new();
-            for target in &self.targets {
-                let zone = target.zone.clone();
-                if !dns_regions.contains(&(zone.clone(), target.dns.clone())) {
-                    dns_regions.push((zone.clone(), target.dns.clone()))
-                }
-            }
-            let mut existing_entries = HashMap::new();
-            for (zone, dns) in dns_regions {
-                if let Ok(response) = self.list_dns(zone.as_str(), dns.clone()).await {
-                    response.result.iter()
-                        .map(|dns| {
-                            match dns.content {
-                                DnsContent::A { content } => { Some(content) }
-                                _ => { None }
-                            }
-                        })
-                        .for_each(|opt_ip| {
-                            if let Some(ip) = opt_ip {
-                                existing_entries.insert((zone.clone(), dns.clone()), ip);
-                            }
-                        });
-                }
+            self.elect_leader().await;
+            self.load_state();
+            let targets = self.targets.clone();
+            self.inventory(&targets).await;
+            // drained targets stay out of DNS, even if their record was added in the meantime
+            for id in self.drained.clone() {
+                self.drain_target(id).await;
             }
+            self.publish_status();
+            self.save_state().await;
 
-            for target in &self.targets {
-                if !existing_entries.contains_key(&(target.zone.clone(), target.dns.clone())) {
-                    info!("{} -> {} DNS entry did not exist at startup, assuming it is unavailable",
-                          target.dns, target.target.to_string());
-                    self.unavailable.push(target.target);
-                }
-            }
-
-
-            // loop checks
+            // every target is checked by its own task, the results are handled one at a time
+            let (result_sender, mut results) = mpsc::channel::<CheckResult>(64);
+            let mut schedules = self.targets.iter()
+                .map(|target| (target.clone(), self.schedule_checks(target.clone(), interval, result_sender.clone())))
+                .collect::<Vec<(ServiceTarget, JoinHandle<()>)>>();
+            let reconcile_period = reconcile_interval.unwrap_or(interval);
+            let mut reconcile_timer = tokio::time::interval_at(Instant::now() + reconcile_period, reconcile_period);
+            let mut election_timer = tokio::time::interval_at(Instant::now() + LEADER_ELECTION_INTERVAL, LEADER_ELECTION_INTERVAL);
             loop {
-                info!("Running health check");
-                let start = SystemTime::now();
-                let handles = self.targets.iter()
-                    .map(|target| {
-                        let top_target = target.clone();
-                        let http_client = http_client.clone();
-                        let target = top_target.clone();
-                        let handle = tokio::spawn(async move {
-                            let base_addr = target.target;
-                            let timeout_ms = target.response_threshold_ms.unwrap_or(1000);
-                            let timeout = Duration::from_millis(timeout_ms as u64);
-                            let service_uri = target.check;
-                            let request_start = SystemTime::now();
-                            let up = match service_uri {
-                                ServiceUri::Icmp => {
-                                    debug!("Checking ICMP {}", base_addr.to_string());
-                                    #[cfg(not(target_env = "msvc"))]
-                                        let result = spawn_blocking(move || {
-                                        let mut ping = Ping::new();
-                                        ping.set_timeout(timeout.as_secs_f64()).ok();
-                                        ping.add_host(base_addr.to_string().as_str()).ok();
-                                        let success = ping.send().map(|iter| {
-                                            let mut success = false;
-                                            for _item in iter {
-                                                // todo: make sure pings were successful
-                                                success = true
-                                            }
-                                            success
-                                        }).unwrap_or(false);
-                                        success
-                                    }).await.unwrap_or(false);
-                                    #[cfg(target_env = "msvc")]
-                                        let result = false;
-                                    result
-                                }
-                                ServiceUri::TcpProbe(port) => {
-                                    debug!("Checking TCP Probe {}:{}", base_addr.to_string(), port);
-                                    let addr = SocketAddr::new(IpAddr::from(base_addr), port);
-                                    spawn_blocking(move || {
-                                        let start = SystemTime::now();
-                                        match TcpStream::connect_timeout(&addr, timeout) {
-                                            Ok(_) => {
-                                                debug!("TCP Probe succeeded");
-                                                start.elapsed()
-                                                    .map(|duration| duration.as_millis() < timeout.as_millis())
-                                                    .unwrap_or(false)
-                                            }
-                                            Err(_) => {
-                                                debug!("TCP Probe failed");
-                                                false
-                                            }
-                                        }
-                                    }).await.unwrap_or(false)
-                                }
-                                ServiceUri::Http(port, method, route) => {
-                                    let mut uri = "http://".to_owned();
-                                    uri.push_str(base_addr.to_string().as_str());
-                                    uri.push_str(":");
-                                    uri.push_str(port.to_string().as_str());
-                                    if !route.starts_with("/") {
-                                        uri.push_str("/");
-                                    }
-                                    uri.push_str(route.as_str());
-                                    HealthChecker::http_check(http_client.clone(), method, uri, timeout).await
-                                }
-                                ServiceUri::HttpSecure(port, method, route) => {
-                                    let mut uri = "https://".to_owned();
-                                    uri.push_str(base_addr.to_string().as_str());
-                                    uri.push_str(":");
-                                    uri.push_str(port.to_string().as_str());
-                                    if !route.starts_with("/") {
-                                        uri.push_str("/");
-                                    }
-                                    uri.push_str(route.as_str());
-                                    HealthChecker::http_check(http_client.clone(), method, uri, timeout).await
-                                }
-                            };
-                            let target_host = base_addr.to_string();
-                            let target_label = [target_host.as_str()];
-                            if let Ok(request_duration) = request_start.elapsed() {
-                                HEALTHCHECK_REQUEST_TIME
-                                    .with_label_values(&target_label)
-                                    .observe(request_duration.as_secs_f64());
-                            }
-                            TARGETS_STATUS
-                                .with_label_values(&target_label)
-                                .set(if up { 1 } else { 0 });
-                            if up {
-                                info!("Target {} is up", base_addr.to_string());
+                tokio::select! {
+                    _ = reconcile_timer.tick(), if reconcile_interval.is_some() => {
+                        self.reconcile().await;
+                    }
+                    _ = election_timer.tick(), if self.leader_election.is_some() => {
+                        // a new leader keeps the drains of the previous one and brings the records in line with
+                        // the state it tracked as follower
+                        if self.elect_leader().await {
+                            self.take_over_drains().await;
+                            self.reconcile().await;
+                        }
+                    }
+                    Some(result) = results.recv() => {
+                        // results of targets which were removed or changed by a reload are dropped
+                        if !self.targets.contains(&result.target) {
+                            continue;
+                        }
+                        self.handle_check_result(result.target, result.up, result.duration).await;
+                    }
+                    Some(command) = commands.recv() => {
+                        self.handle_command(command).await;
+                        // reschedule the targets which were changed by a reload
+                        let targets = &self.targets;
+                        schedules.retain(|(target, handle)| {
+                            if targets.contains(target) {
+                                true
                             } else {
-                                warn!("Target {} is down", base_addr.to_string());
+                                handle.abort();
+                                false
                             }
-                            up
                         });
-                        (top_target.clone(), handle)
-                    })
-                    .collect::<Vec<(ServiceTarget, JoinHandle<bool>)>>();
-                for (target, handle) in handles {
-                    match handle.await {
-                        Ok(up) => {
-                            if up {
-                                self.handle_target_up(target.clone()).await;
-                            } else {
-                                self.handle_target_down(target.clone()).await;
+                        for target in self.targets.iter() {
+                            if !schedules.iter().any(|(scheduled, _)| scheduled.eq(target)) {
+                                schedules.push((target.clone(), self.schedule_checks(target.clone(), interval, result_sender.clone())));
                             }
                         }
-                        Err(err) => {
-                            warn!("An error occurred when trying to join child handle: {}", err);
-                            self.handle_target_down(target.clone()).await;
-                        }
                     }
                 }
-                let elapsed = start.elapsed().unwrap_or(Duration::from_millis(0));
-                info!("Completed after {}s", elapsed.as_secs_f32());
-                let sleep_duration = if elapsed.lt(&interval) {
-                    interval.sub(elapsed)
-                } else {
-                    Duration::from_secs(0)
-                };
                 TARGETS_AVAILABLE.set((self.targets.len() - self.unavailable.len()) as i64);
-                debug!("Sleeping for another {}s before next health check", sleep_duration.as_secs_f32());
+                self.publish_status();
+                self.save_state().await;
+            }
+        })
+    }
+
+    /// Checks the target every interval plus a random jitter until the task is aborted
+    fn schedule_checks(&self, target: ServiceTarget, default_interval: Duration, results: Sender<CheckResult>) -> JoinHandle<()> {
+        let http_clients = self.http_clients.get(&target.key()).cloned().unwrap_or_default();
+        let interval = target.interval.unwrap_or(default_interval);
+        let jitter = target.jitter;
+        tokio::spawn(async move {
+            // spreads the first checks of targets sharing the same interval
+            tokio::time::sleep(HealthChecker::random_jitter(jitter)).await;
+            loop {
+                let start = Instant::now();
+                let handle = tokio::spawn(HealthChecker::check_target(http_clients.clone(), target.clone()));
+                let (up, duration) = match handle.await {
+                    Ok((up, duration)) => { (up, Some(duration)) }
+                    Err(err) => {
+                        warn!("An error occurred when trying to join child handle: {}", err);
+                        (false, None)
+                    }
+                };
+                let result = CheckResult { target: target.clone(), up, duration };
+                if results.send(result).await.is_err() {
+                    break;
+                }
+                let sleep_duration = interval.checked_sub(start.elapsed()).unwrap_or_default()
+                    + HealthChecker::random_jitter(jitter);
+                debug!("Sleeping for another {}s before next health check of {}", sleep_duration.as_secs_f32(), target.id);
                 tokio::time::sleep(sleep_duration).await;
             }
         })
     }
 
+    fn random_jitter(jitter: Duration) -> Duration {
+        if jitter.is_zero() {
+            return Duration::from_secs(0);
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0..=jitter.as_millis() as u64))
+    }
+
+    /// Runs all checks of the target and combines their results
+    async fn check_target(http_clients: Vec<Option<HttpCheckClients>>, target: ServiceTarget) -> (bool, Duration) {
+        let base_addr = target.target;
+        let timeout_ms = target.response_threshold_ms.unwrap_or(1000);
+        let timeout = Duration::from_millis(timeout_ms as u64);
+        let request_start = SystemTime::now();
+        let target_host = base_addr.to_string();
+        let target_id = target.id.clone();
+        let check_count = target.checks.len();
+        let check_handles = target.checks.into_iter()
+            .enumerate()
+            .map(|(index, check)| {
+                let check_label = format!("{}-{}", index, check.kind());
+                let http_client = http_clients.get(index).cloned().flatten();
+                let handle = tokio::spawn(HealthChecker::run_check(http_client, base_addr, target_id.clone(), check, timeout));
+                (check_label, handle)
+            })
+            .collect::<Vec<(String, JoinHandle<bool>)>>();
+        let mut passed = 0;
+        for (check_label, handle) in check_handles {
+            let check_up = handle.await.unwrap_or(false);
+            CHECK_STATUS
+                .with_label_values(&[target_host.as_str(), target_id.as_str(), check_label.as_str()])
+                .set(if check_up { 1 } else { 0 });
+            if check_count > 1 {
+                if check_up {
+                    info!("Target {} check {} passed", target_host, check_label);
+                } else {
+                    warn!("Target {} check {} failed", target_host, check_label);
+                }
+            }
+            if check_up {
+                passed += 1;
+            }
+        }
+        let up = target.combinator.is_up(passed, check_count);
+        let target_label = [target_host.as_str(), target_id.as_str()];
+        let request_duration = request_start.elapsed()
+            .unwrap_or(Duration::from_millis(0));
+        HEALTHCHECK_REQUEST_TIME
+            .with_label_values(&target_label)
+            .observe(request_duration.as_secs_f64());
+        TARGETS_STATUS
+            .with_label_values(&target_label)
+            .set(if up { 1 } else { 0 });
+        if up {
+            info!("Target {} is up", base_addr.to_string());
+        } else {
+            warn!("Target {} is down", base_addr.to_string());
+        }
+        (up, request_duration)
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Reload(targets) => {
+                self.apply_targets(targets).await;
+            }
+            Command::Drain(id) => {
+                self.drain_target(id).await;
+            }
+            Command::Undrain(id) => {
+                self.undrain_target(id).await;
+            }
+        }
+    }
+
+    async fn drain_target(&mut self, id: String) {
+        let target = match self.targets.iter().find(|target| target.id.eq(&id)) {
+            Some(target) => { target.clone() }
+            None => {
+                warn!("Cannot drain unknown target {}", id);
+                return;
+            }
+        };
+        if !self.drained.contains(&id) {
+            self.drained.push(id);
+        }
+        if !self.unavailable.contains(&target.key()) {
+            self.remove_target_record(&target).await;
+            self.unavailable.push(target.key());
+        }
+        warn!("Target {} was drained", target.target);
+    }
+
+    async fn undrain_target(&mut self, id: String) {
+        let target = match self.targets.iter().find(|target| target.id.eq(&id)) {
+            Some(target) => { target.clone() }
+            None => {
+                warn!("Cannot undrain unknown target {}", id);
+                return;
+            }
+        };
+        self.drained.retain(|other| !id.eq(other));
+        if self.unavailable.contains(&target.key()) {
+            self.add_target_record(&target).await;
+            let key = target.key();
+            self.unavailable.retain(|other| !key.eq(other));
+        }
+        info!("Target {} was undrained", target.target);
+    }
+
+    /// Replaces the contents of the status board with the current state of all targets
+    fn publish_status(&self) {
+        let status = self.targets.iter()
+            .map(|target| {
+                let health = self.health.get(&target.key()).cloned().unwrap_or_default();
+                TargetStatus {
+                    id: target.id.clone(),
+                    ip: target.target,
+                    zone: target.zone.clone(),
+                    dns: target.dns.clone(),
+                    healthy: health.healthy,
+                    in_dns: !self.unavailable.contains(&target.key()),
+                    drained: self.drained.contains(&target.id),
+                    last_check_up: health.last_result,
+                    last_check_duration_ms: health.last_duration.map(|duration| duration.as_secs_f64() * 1000.0),
+                    consecutive_successes: health.successes,
+                    consecutive_failures: health.failures,
+                    last_transition: health.last_transition.and_then(HealthChecker::unix_seconds),
+                }
+            })
+            .collect();
+        *self.status.write().unwrap() = status;
+    }
+
+    /// Acquires or renews the leadership, returns whether this replica just became the leader
+    async fn elect_leader(&mut self) -> bool {
+        let leader_election = match &self.leader_election {
+            Some(leader_election) => { leader_election.clone() }
+            None => {
+                LEADER.set(1);
+                return false;
+            }
+        };
+        let leading = match leader_election.try_lead().await {
+            Ok(leading) => { leading }
+            Err(error) => {
+                warn!("Leader election failed, acting as follower: {}", error);
+                false
+            }
+        };
+        let promoted = leading && !self.leading;
+        if promoted {
+            info!("This replica is now the leader and changes DNS records");
+        } else if self.leading && !leading {
+            warn!("This replica lost the leadership and no longer changes DNS records");
+        }
+        self.leading = leading;
+        let leader = if leading { None } else { leader_election.leader().await };
+        *self.leadership.write().unwrap() = LeaderStatus { leading, leader };
+        LEADER.set(if leading { 1 } else { 0 });
+        promoted
+    }
+
+    /// Restores the health and drains of the targets which did not change since the state was written
+    fn load_state(&mut self) {
+        let state = match &self.state_file {
+            Some(state_file) => { state_file.load() }
+            None => { return; }
+        };
+        for saved in &state.targets {
+            let target = self.targets.iter().find(|target| {
+                target.id.eq(&saved.id) && target.zone.eq(&saved.zone) && target.dns.eq(&saved.dns) && target.target.eq(&saved.ip)
+            });
+            if let Some(target) = target {
+                self.health.insert(target.key(), TargetHealth {
+                    successes: saved.successes,
+                    failures: saved.failures,
+                    healthy: saved.healthy,
+                    last_result: saved.last_result,
+                    last_duration: None,
+                    last_transition: saved.last_transition.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)),
+                });
+            }
+        }
+        self.drained = self.known_targets(state.drained);
+        info!("Restored the state of {} targets and {} drains", self.health.len(), self.drained.len());
+    }
+
+    /// Applies the drains written by the previous leader, the health tracked as follower is kept as it is more recent
+    async fn take_over_drains(&mut self) {
+        let drained = match &self.state_file {
+            Some(state_file) => { self.known_targets(state_file.load().drained) }
+            None => { return; }
+        };
+        for id in self.drained.clone() {
+            if !drained.contains(&id) {
+                self.undrain_target(id).await;
+            }
+        }
+        for id in drained {
+            self.drain_target(id).await;
+        }
+    }
+
+    /// Leaves out the ids of targets which are not checked anymore
+    fn known_targets(&self, ids: Vec<String>) -> Vec<String> {
+        ids.into_iter()
+            .filter(|id| self.targets.iter().any(|target| target.id.eq(id)))
+            .collect()
+    }
+
+    /// Writes the state file at once after transitions and drains, and at most every `STATE_FLUSH_INTERVAL`
+    /// if only the check counters changed. Only the leader writes it, as replicas may share the file
+    async fn save_state(&mut self) {
+        let state_file = match &self.state_file {
+            Some(state_file) if self.leading => { state_file.clone() }
+            _ => { return; }
+        };
+        let state = SerializedState {
+            targets: self.targets.iter()
+                .filter_map(|target| {
+                    self.health.get(&target.key()).map(|health| SerializedTargetState {
+                        id: target.id.clone(),
+                        zone: target.zone.clone(),
+                        dns: target.dns.clone(),
+                        ip: target.target,
+                        healthy: health.healthy,
+                        successes: health.successes,
+                        failures: health.failures,
+                        last_result: health.last_result,
+                        last_transition: health.last_transition.and_then(HealthChecker::unix_seconds),
+                    })
+                })
+                .collect(),
+            drained: self.drained.clone(),
+        };
+        if state == self.saved_state {
+            return;
+        }
+        let transitions = |state: &SerializedState| state.targets.iter()
+            .map(|target| (target.id.clone(), target.zone.clone(), target.dns.clone(), target.ip, target.healthy, target.last_transition))
+            .collect::<Vec<_>>();
+        let transitioned = state.drained != self.saved_state.drained
+            || transitions(&state) != transitions(&self.saved_state);
+        if !transitioned && self.state_flushed.elapsed() < STATE_FLUSH_INTERVAL {
+            return;
+        }
+        let written = state.clone();
+        if let Err(error) = spawn_blocking(move || state_file.save(&written)).await {
+            warn!("Writing the state file was interrupted: {}", error);
+        }
+        self.saved_state = state;
+        self.state_flushed = Instant::now();
+    }
+
+    fn unix_seconds(time: SystemTime) -> Option<u64> {
+        time.duration_since(UNIX_EPOCH).ok().map(|duration| duration.as_secs())
+    }
+
+    /// Marks targets as unavailable if their DNS entry does not exist
+    async fn inventory(&mut self, targets: &[ServiceTarget]) {
+        let mut dns_regions = Vec::new();
+        for target in targets {
+            let zone = target.zone.clone();
+            if !dns_regions.contains(&(target.mode.clone(), zone.clone(), target.dns.clone())) {
+                dns_regions.push((target.mode.clone(), zone.clone(), target.dns.clone()))
+            }
+        }
+        // every address per name, names which couldn't be listed are left out
+        let mut existing_entries: HashMap<(TargetMode, String, String), Vec<IpAddr>> = HashMap::new();
+        for (mode, zone, dns) in dns_regions {
+            match self.provider(&mode).list_records(zone.as_str(), dns.as_str()).await {
+                Ok(records) => {
+                    existing_entries.insert((mode, zone, dns), records);
+                }
+                Err(error) => {
+                    warn!("{}", error);
+                }
+            }
+        }
+
+        for target in targets {
+            let exists = existing_entries.get(&(target.mode.clone(), target.zone.clone(), target.dns.clone()))
+                .map(|records| records.contains(&target.target))
+                .unwrap_or(false);
+            if !exists {
+                info!("{} did not exist, assuming it is unavailable",
+                      target.mode.describe(target.dns.as_str(), target.target));
+                if !self.unavailable.contains(&target.key()) {
+                    self.unavailable.push(target.key());
+                }
+            }
+        }
+    }
+
+    /// Replaces the checked targets, keeping the state of targets which are still configured
+    async fn apply_targets(&mut self, targets: Vec<ServiceTarget>) {
+        let same_entry = |a: &ServiceTarget, b: &ServiceTarget| a.key().eq(&b.key());
+        let removed = self.targets.iter()
+            .filter(|old| !targets.iter().any(|new| same_entry(old, new)))
+            .cloned()
+            .collect::<Vec<ServiceTarget>>();
+        let added = targets.iter()
+            .filter(|new| !self.targets.iter().any(|old| same_entry(old, new)))
+            .cloned()
+            .collect::<Vec<ServiceTarget>>();
+        info!("Reloaded targets: {} added, {} removed, {} kept",
+              added.len(), removed.len(), targets.len() - added.len());
+
+        // clients of targets whose checks changed are built again
+        let previous = &self.targets;
+        self.http_clients.retain(|key, _| targets.iter().any(|target| target.key().eq(key) && previous.contains(target)));
+        self.targets = targets;
+        self.build_http_clients();
+        self.inventory(&added).await;
+
+        for target in &removed {
+            if !self.unavailable.contains(&target.key()) {
+                let remaining = self.targets.iter()
+                    .filter(|inner| inner.mode.eq(&target.mode) && inner.zone.eq(&target.zone) && inner.dns.eq(&target.dns))
+                    .filter(|inner| !self.unavailable.contains(&inner.key()))
+                    .count();
+                if remaining > 0 {
+                    self.remove_target_record(target).await;
+                } else {
+                    warn!("Target {} was removed. Not taking it out of rotation due to being the last target of {}",
+                          target.target, target.dns);
+                }
+            }
+        }
+
+        // forget the state of targets which are not checked anymore
+        let targets = &self.targets;
+        self.unavailable.retain(|key| targets.iter().any(|target| target.key().eq(key)));
+        self.health.retain(|key, _| targets.iter().any(|target| target.key().eq(key)));
+        self.drained.retain(|id| targets.iter().any(|target| target.id.eq(id)));
+        for target in &removed {
+            if !self.targets.iter().any(|inner| inner.target.eq(&target.target) && inner.id.eq(&target.id)) {
+                let target_host = target.target.to_string();
+                let target_label = [target_host.as_str(), target.id.as_str()];
+                TARGETS_STATUS.remove_label_values(&target_label).ok();
+                TARGETS_CONSECUTIVE_SUCCESSES.remove_label_values(&target_label).ok();
+                TARGETS_CONSECUTIVE_FAILURES.remove_label_values(&target_label).ok();
+                HEALTHCHECK_REQUEST_TIME.remove_label_values(&target_label).ok();
+                ICMP_RTT.remove_label_values(&target_label).ok();
+                ICMP_LOSS.remove_label_values(&target_label).ok();
+                TCP_CONNECT_TIME.remove_label_values(&target_label).ok();
+                TLS_CERTIFICATE_EXPIRY_DAYS.remove_label_values(&target_label).ok();
+                for (index, check) in target.checks.iter().enumerate() {
+                    let check_label = format!("{}-{}", index, check.kind());
+                    CHECK_STATUS.remove_label_values(&[target_host.as_str(), target.id.as_str(), check_label.as_str()]).ok();
+                }
+            }
+        }
+    }
+
+    async fn handle_check_result(&mut self, target: ServiceTarget, up: bool, duration: Option<Duration>) {
+        let health = self.health.entry(target.key()).or_default();
+        let was_healthy = health.healthy;
+        if up {
+            health.successes = health.successes.saturating_add(1);
+            health.failures = 0;
+            if health.successes >= target.rise {
+                health.healthy = Some(true);
+            }
+        } else {
+            health.failures = health.failures.saturating_add(1);
+            health.successes = 0;
+            if health.failures >= target.fall {
+                health.healthy = Some(false);
+            }
+        }
+        if health.healthy != was_healthy {
+            health.last_transition = Some(SystemTime::now());
+        }
+        health.last_result = Some(up);
+        health.last_duration = duration;
+        let counter = health.clone();
+
+        let target_host = target.target.to_string();
+        let target_label = [target_host.as_str(), target.id.as_str()];
+        TARGETS_CONSECUTIVE_SUCCESSES
+            .with_label_values(&target_label)
+            .set(counter.successes as i64);
+        TARGETS_CONSECUTIVE_FAILURES
+            .with_label_values(&target_label)
+            .set(counter.failures as i64);
+
+        if self.drained.contains(&target.id) {
+            debug!("Target {} is drained, not changing its rotation", target_host);
+            return;
+        }
+
+        let unavailable = self.unavailable.contains(&target.key());
+        if up {
+            if counter.successes >= target.rise {
+                self.handle_target_up(target).await;
+            } else if unavailable {
+                info!("Target {} passed {}/{} checks required to rise",
+                      target_host, counter.successes, target.rise);
+            }
+        } else if counter.failures >= target.fall {
+            self.handle_target_down(target).await;
+        } else if !unavailable {
+            warn!("Target {} failed {}/{} checks required to fall",
+                  target_host, counter.failures, target.fall);
+        }
+    }
+
     async fn handle_target_up(&mut self, target: ServiceTarget) {
-        if self.unavailable.contains(&target.target) {
-            self.cloudflare_add_target(&target).await;
+        if self.unavailable.contains(&target.key()) {
+            self.add_target_record(&target).await;
             // retain all targets which are not this target
-            self.unavailable.retain(|other| !target.target.eq(other));
+            let key = target.key();
+            self.unavailable.retain(|other| !key.eq(other));
             info!("Target {} is available again", target.target.to_string());
         }
     }
 
     async fn handle_target_down(&mut self, target: ServiceTarget) {
-        if !self.unavailable.contains(&target.target) {
+        if !self.unavailable.contains(&target.key()) {
             let dns_targets = self.targets.iter()
-                .filter(|inner| inner.dns.eq(&target.dns))
+                .filter(|inner| inner.mode.eq(&target.mode) && inner.zone.eq(&target.zone) && inner.dns.eq(&target.dns))
                 .collect::<Vec<&ServiceTarget>>();
             let dns_unavailable = dns_targets.iter()
-                .filter(|inner| self.unavailable.contains(&inner.target))
+                .filter(|inner| self.unavailable.contains(&inner.key()))
                 .collect::<Vec<&&ServiceTarget>>();
             let available = dns_targets.len() - dns_unavailable.len();
             if available > 1 {
-                self.cloudflare_remove_target(&target).await;
-                self.unavailable.push(target.target);
+                self.remove_target_record(&target).await;
+                self.unavailable.push(target.key());
                 warn!("Target {} went unavailable", target.target.to_string());
             } else {
-                warn!("Target {} is unavailable. Not removing from CF due to being the last target",
+                warn!("Target {} is unavailable. Not taking it out of rotation due to being the last target",
                       target.target.to_string());
             }
         } else {
@@ -232,114 +679,769 @@ impl HealthChecker {
         }
     }
 
-    async fn http_check(client: reqwest::Client, method: Method, uri: String, timeout: Duration) -> bool {
-        debug!("Checking {} {}", method.as_str(), uri.as_str());
-        let request = client.request(method, uri)
-            .timeout(timeout)
-            .build().unwrap();
-        match client.execute(request).await {
-            Ok(response) => {
-                let status_code = response.status();
-                debug!("Response Status: {}", status_code.as_u16());
-                status_code.is_success()
+    /// Compares the records of every managed name with the available targets and fixes the differences
+    async fn reconcile(&self) {
+        if !self.leading {
+            debug!("Not reconciling DNS records as follower");
+            return;
+        }
+        debug!("Reconciling DNS records");
+        let mut names: Vec<(TargetMode, String, String)> = Vec::new();
+        for target in &self.targets {
+            let name = (target.mode.clone(), target.zone.clone(), target.dns.clone());
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        for (mode, zone, dns) in names {
+            let provider = self.provider(&mode);
+            let records = match provider.list_records(zone.as_str(), dns.as_str()).await {
+                Ok(records) => { records }
+                Err(error) => {
+                    warn!("Couldn't reconcile {}: {}", dns, error);
+                    continue;
+                }
+            };
+            let desired = self.targets.iter()
+                .filter(|target| target.mode.eq(&mode) && target.zone.eq(&zone) && target.dns.eq(&dns))
+                .filter(|target| !self.unavailable.contains(&target.key()))
+                .map(|target| target.target)
+                .collect::<Vec<IpAddr>>();
+            for ip in desired.iter().filter(|ip| !records.contains(ip)) {
+                let record = mode.describe(dns.as_str(), *ip);
+                warn!("{} is missing, putting it into rotation", record);
+                match provider.add_record(zone.as_str(), dns.as_str(), *ip).await {
+                    Ok(_) => { DRIFT_CORRECTIONS.with_label_values(&["add"]).inc(); }
+                    Err(error) => { warn!("Couldn't put {} into rotation: {}", record, error); }
+                }
+            }
+            // like the last target of a name, records are kept while no target is available
+            if desired.is_empty() {
+                continue;
+            }
+            for ip in records.iter().filter(|ip| !desired.contains(ip)) {
+                let record = mode.describe(dns.as_str(), *ip);
+                warn!("{} is not expected, taking it out of rotation", record);
+                match provider.remove_record(zone.as_str(), dns.as_str(), *ip).await {
+                    Ok(_) => { DRIFT_CORRECTIONS.with_label_values(&["remove"]).inc(); }
+                    Err(error) => { warn!("Couldn't take {} out of rotation: {}", record, error); }
+                }
+            }
+        }
+    }
+
+    /// Runs a single check against the target
+    async fn run_check(http_client: Option<HttpCheckClients>, base_addr: IpAddr, target_id: String, service_uri: ServiceUri, timeout: Duration) -> bool {
+        match service_uri {
+            ServiceUri::Icmp(check) => {
+                debug!("Checking ICMP {}", base_addr.to_string());
+                HealthChecker::icmp_check(base_addr, target_id.as_str(), check, timeout).await
+            }
+            ServiceUri::TcpProbe(check) => {
+                debug!("Checking TCP Probe {}:{}", base_addr.to_string(), check.port);
+                HealthChecker::tcp_check(base_addr, target_id.as_str(), check, timeout).await
+            }
+            ServiceUri::Http(check) => {
+                let mut uri = "http://".to_owned();
+                uri.push_str(HealthChecker::http_authority(base_addr, &check).as_str());
+                if !check.route.starts_with("/") {
+                    uri.push_str("/");
+                }
+                uri.push_str(check.route.as_str());
+                HealthChecker::http_check(http_client, &check, uri, timeout, base_addr, target_id.as_str()).await
+            }
+            ServiceUri::HttpSecure(check) => {
+                let mut uri = "https://".to_owned();
+                uri.push_str(HealthChecker::http_authority(base_addr, &check).as_str());
+                if !check.route.starts_with("/") {
+                    uri.push_str("/");
+                }
+                uri.push_str(check.route.as_str());
+                HealthChecker::http_check(http_client, &check, uri, timeout, base_addr, target_id.as_str()).await
+            }
+            ServiceUri::Grpc(check) => {
+                debug!("Checking gRPC {}:{}", base_addr, check.port);
+                HealthChecker::grpc_check(base_addr, check, timeout).await
+            }
+            ServiceUri::Dns(check) => {
+                debug!("Checking DNS {}:{}", base_addr, check.port);
+                HealthChecker::dns_check(base_addr, check, timeout).await
+            }
+            ServiceUri::Exec(check) => {
+                debug!("Checking Exec {} for {}", check.command, base_addr);
+                HealthChecker::exec_check(base_addr, check, timeout).await
+            }
+        }
+    }
+
+    /// Connects within the timeout, optionally sends a payload and waits for the expected response
+    async fn tcp_check(target: IpAddr, target_id: &str, check: TcpCheck, timeout: Duration) -> bool {
+        let addr = SocketAddr::new(target, check.port);
+        let start = Instant::now();
+        let result = tokio::time::timeout(timeout, async {
+            let mut stream = TcpStream::connect(addr).await?;
+            let connect_time = start.elapsed();
+            if let Some(payload) = &check.send {
+                stream.write_all(payload.as_bytes()).await?;
+            }
+            let mut matched = true;
+            if let Some(expected) = &check.expect {
+                let mut received = Vec::new();
+                let mut buffer = [0u8; 1024];
+                matched = false;
+                while received.len() < MAX_TCP_RESPONSE_BYTES {
+                    let read = stream.read(&mut buffer).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buffer[..read]);
+                    if String::from_utf8_lossy(&received).contains(expected.as_str()) {
+                        matched = true;
+                        break;
+                    }
+                }
+            }
+            Ok::<(Duration, bool), std::io::Error>((connect_time, matched))
+        }).await;
+
+        match result {
+            Ok(Ok((connect_time, matched))) => {
+                let target_host = target.to_string();
+                TCP_CONNECT_TIME
+                    .with_label_values(&[target_host.as_str(), target_id])
+                    .set(connect_time.as_secs_f64() * 1000.0);
+                debug!("TCP Probe connected after {}ms", connect_time.as_millis());
+                if !matched {
+                    debug!("TCP Probe did not receive the expected response");
+                }
+                matched
+            }
+            Ok(Err(err)) => {
+                debug!("TCP Probe failed: {}", err);
+                false
             }
             Err(_) => {
-                debug!("Response Status: 0 (Error)");
+                debug!("TCP Probe timed out");
                 false
             }
         }
     }
 
-    async fn cloudflare_add_target(&self, target: &ServiceTarget) {
-        let target = target.clone();
-        if let Ok(response) = self.list_dns(target.zone.as_str(), target.dns.clone()).await {
-            let response: ApiSuccess<Vec<DnsRecord>> = response;
-            if response.errors.len() != 0 {
-                response.errors.iter().for_each(|e| warn!("CF Api Error: {}", e));
-                return;
+    #[cfg(not(target_env = "msvc"))]
+    async fn icmp_check(target: IpAddr, target_id: &str, check: IcmpCheck, timeout: Duration) -> bool {
+        let count = check.count;
+        let rtts = spawn_blocking(move || {
+            // every echo reply carries its latency, lost echo requests are left out
+            let mut rtts = Vec::new();
+            for _ in 0..count {
+                let mut ping = Ping::new();
+                ping.set_timeout(timeout.as_secs_f64()).ok();
+                if ping.add_host(target.to_string().as_str()).is_err() {
+                    continue;
+                }
+                if let Ok(iter) = ping.send() {
+                    for item in iter {
+                        if item.dropped == 0 && item.latency_ms >= 0.0 {
+                            rtts.push(item.latency_ms);
+                        }
+                    }
+                }
+            }
+            rtts
+        }).await.unwrap_or_default();
+
+        let target_host = target.to_string();
+        let target_label = [target_host.as_str(), target_id];
+        let loss_percent = (count as usize - rtts.len().min(count as usize)) as f64 * 100.0 / count as f64;
+        ICMP_LOSS
+            .with_label_values(&target_label)
+            .set(loss_percent);
+        if rtts.is_empty() {
+            ICMP_RTT.remove_label_values(&target_label).ok();
+            debug!("ICMP {}: all {} echo requests lost", target_host, count);
+            return false;
+        }
+        let average_rtt = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        ICMP_RTT
+            .with_label_values(&target_label)
+            .set(average_rtt);
+        debug!("ICMP {}: {:.1}% loss, {:.2}ms average rtt", target_host, loss_percent, average_rtt);
+
+        loss_percent <= check.max_loss_percent
+            && check.max_rtt_ms.map(|max_rtt| average_rtt <= max_rtt).unwrap_or(true)
+    }
+
+    #[cfg(target_env = "msvc")]
+    async fn icmp_check(_target: IpAddr, _target_id: &str, _check: IcmpCheck, _timeout: Duration) -> bool {
+        false
+    }
+
+    /// Calls `grpc.health.v1.Health/Check`, only `SERVING` is considered healthy
+    async fn grpc_check(target: IpAddr, check: GrpcCheck, timeout: Duration) -> bool {
+        let scheme = if check.tls { "https" } else { "http" };
+        let uri = format!("{}://{}", scheme, SocketAddr::new(target, check.port));
+        let mut endpoint = match Endpoint::from_shared(uri) {
+            Ok(endpoint) => { endpoint.connect_timeout(timeout).timeout(timeout) }
+            Err(err) => {
+                debug!("Invalid gRPC endpoint: {}", err);
+                return false;
+            }
+        };
+        if check.tls {
+            let mut tls_config = ClientTlsConfig::new();
+            if let Some(hostname) = &check.hostname {
+                tls_config = tls_config.domain_name(hostname.as_str());
+            }
+            endpoint = match endpoint.tls_config(tls_config) {
+                Ok(endpoint) => { endpoint }
+                Err(err) => {
+                    debug!("Invalid gRPC TLS config: {}", err);
+                    return false;
+                }
+            };
+        }
+
+        let result = tokio::time::timeout(timeout, async {
+            let channel = endpoint.connect().await
+                .map_err(|err| err.to_string())?;
+            HealthClient::new(channel)
+                .check(HealthCheckRequest { service: check.service.clone() })
+                .await
+                .map_err(|status| status.to_string())
+        }).await;
+
+        match result {
+            Ok(Ok(response)) => {
+                let status = response.into_inner().status();
+                debug!("gRPC health status: {:?}", status);
+                status == ServingStatus::Serving
+            }
+            Ok(Err(err)) => {
+                debug!("gRPC health check failed: {}", err);
+                false
+            }
+            Err(_) => {
+                debug!("gRPC health check timed out");
+                false
+            }
+        }
+    }
+
+    /// Queries the target, requires NOERROR and the expected answer if configured
+    async fn dns_check(target: IpAddr, check: DnsCheck, timeout: Duration) -> bool {
+        let addr = SocketAddr::new(target, check.port);
+        let result = tokio::time::timeout(timeout, async {
+            let mut client = match check.protocol {
+                DnsProtocol::Udp => {
+                    let stream = UdpClientStream::<tokio::net::UdpSocket>::with_timeout(addr, timeout);
+                    let (client, background) = AsyncClient::connect(stream).await?;
+                    tokio::spawn(background);
+                    client
+                }
+                DnsProtocol::Tcp => {
+                    let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::with_timeout(addr, timeout);
+                    let (client, background) = AsyncClient::with_timeout(stream, sender, timeout, None).await?;
+                    tokio::spawn(background);
+                    client
+                }
+            };
+            client.query(check.name.clone(), DNSClass::IN, check.record_type).await
+        }).await;
+
+        match result {
+            Ok(Ok(response)) => {
+                let response_code = response.response_code();
+                debug!("DNS response code: {}", response_code);
+                if response_code != ResponseCode::NoError {
+                    return false;
+                }
+                match &check.expected_answer {
+                    Some(expected) => {
+                        let expected = expected.trim_end_matches('.');
+                        let matched = response.answers().iter()
+                            .any(|record| record.rdata().to_string().trim_end_matches('.').eq_ignore_ascii_case(expected));
+                        if !matched {
+                            debug!("DNS answer did not contain {}", expected);
+                        }
+                        matched
+                    }
+                    None => { true }
+                }
+            }
+            Ok(Err(err)) => {
+                debug!("DNS query failed: {}", err);
+                false
             }
-            let exists = response.result.iter()
-                .map(|dns| dns.content.clone())
-                .map(|dns| {
-                    match dns {
-                        DnsContent::A { content } => { Some(content) }
-                        _ => { None }
+            Err(_) => {
+                debug!("DNS query timed out");
+                false
+            }
+        }
+    }
+
+    /// Runs the command with Nagios style exit codes, 0 is up, 1 is degraded but still up and everything else is down
+    async fn exec_check(target: IpAddr, check: ExecCheck, timeout: Duration) -> bool {
+        let target_ip = target.to_string();
+        let target_port = check.port.map(|port| port.to_string()).unwrap_or_default();
+        let args = check.args.iter()
+            .map(|arg| arg.replace("{ip}", target_ip.as_str()).replace("{port}", target_port.as_str()))
+            .collect::<Vec<String>>();
+        let mut command = ProcessCommand::new(check.command.as_str());
+        command.args(&args)
+            .env("TARGET_IP", target_ip.as_str())
+            .env("TARGET_PORT", target_port.as_str())
+            .stdin(std::process::Stdio::null())
+            // the child is killed once the timeout drops the output future
+            .kill_on_drop(true);
+
+        match tokio::time::timeout(timeout, command.output()).await {
+            Ok(Ok(output)) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                debug!("Exec {} exited with {}, stdout: {}, stderr: {}", check.command, output.status, stdout.trim(), stderr.trim());
+                match output.status.code() {
+                    Some(0) => { true }
+                    Some(1) => {
+                        warn!("Target {} is degraded: {}", target_ip, stdout.trim());
+                        true
                     }
+                    _ => { false }
+                }
+            }
+            Ok(Err(err)) => {
+                warn!("Exec {} could not be run: {}", check.command, err);
+                false
+            }
+            Err(_) => {
+                debug!("Exec {} timed out and was killed", check.command);
+                false
+            }
+        }
+    }
+
+    fn http_client_builder() -> reqwest::ClientBuilder {
+        reqwest::ClientBuilder::new()
+            .user_agent("rusty-cloudflare-dns-balancer")
+    }
+
+    /// The host of the check if set, otherwise the target address
+    fn http_authority(target: IpAddr, check: &HttpCheck) -> String {
+        match &check.host {
+            Some(host) => { format!("{}:{}", host, check.port) }
+            // SocketAddr wraps IPv6 addresses in brackets as required for URIs
+            None => { SocketAddr::new(target, check.port).to_string() }
+        }
+    }
+
+    /// Builds the clients of the HTTP(S) checks of the targets which don't have them yet
+    fn build_http_clients(&mut self) {
+        for target in &self.targets {
+            if self.http_clients.contains_key(&target.key()) {
+                continue;
+            }
+            let clients = target.checks.iter()
+                .map(|check| match check {
+                    ServiceUri::Http(check) | ServiceUri::HttpSecure(check) => {
+                        match HealthChecker::http_check_clients_for(&self.http_client, target.target, check) {
+                            Ok(clients) => { Some(clients) }
+                            Err(error) => {
+                                warn!("Couldn't build the HTTP client of target {}, its check will fail: {}", target.id, error);
+                                None
+                            }
+                        }
+                    }
+                    _ => { None }
                 })
-                .any(|opt_addr| {
-                    opt_addr.map(|addr| addr.eq(&target.target))
-                        .unwrap_or(false)
-                });
-            if !exists {
-                let dns_name = target.dns.clone();
-                self.cloudflare_client.request_handle(&CreateDnsRecord {
-                    zone_identifier: target.zone.as_str(),
-                    params: CreateDnsRecordParams {
-                        ttl: None,
-                        priority: None,
-                        proxied: Some(true),
-                        name: dns_name.as_str(),
-                        content: DnsContent::A { content: target.target },
-                    },
-                }).await.ok();
-                CLOUDFLARE_REQUEST_COUNTER
-                    .with_label_values(&["create_dns"])
-                    .inc();
-                info!("Created cloudflare record for {} -> {}", dns_name, target.target.to_string());
-            }
-        }
-    }
-
-    async fn cloudflare_remove_target(&self, target: &ServiceTarget) {
-        let target = target.clone();
-        let result = self.list_dns(target.zone.as_str(), target.dns.clone()).await;
-        match result {
+                .collect();
+            self.http_clients.insert(target.key(), clients);
+        }
+    }
+
+    /// The certificate inspector is only built for checks which inspect the certificate
+    fn http_check_clients_for(client: &reqwest::Client, target: IpAddr, check: &HttpCheck) -> Result<HttpCheckClients, Box<dyn Error>> {
+        let certificate_inspector = match &check.certificate {
+            Some(_) => { Some(HealthChecker::certificate_inspector_for(check)?) }
+            None => { None }
+        };
+        Ok(HttpCheckClients {
+            client: HealthChecker::http_client_for(client, target, check)?,
+            certificate_inspector,
+        })
+    }
+
+    /// Accepts any certificate, the client identity is still presented to targets requiring mutual TLS
+    fn certificate_inspector_for(check: &HttpCheck) -> Result<TlsConnector, native_tls::Error> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+        if let Some((cert, key)) = &check.tls.client_identity {
+            builder.identity(native_tls::Identity::from_pkcs8(cert, key)?);
+        }
+        Ok(TlsConnector::from(builder.build()?))
+    }
+
+    /// Completes a TLS handshake with the target and returns the DER encoded certificate it presented
+    async fn inspect_certificate(inspector: &TlsConnector, target: IpAddr, check: &HttpCheck, timeout: Duration) -> Result<Vec<u8>, String> {
+        let domain = check.host.clone().unwrap_or_else(|| target.to_string());
+        let result = tokio::time::timeout(timeout, async {
+            let stream = TcpStream::connect(SocketAddr::new(target, check.port)).await
+                .map_err(|err| err.to_string())?;
+            let stream = inspector.connect(domain.as_str(), stream).await
+                .map_err(|err| err.to_string())?;
+            let certificate = stream.get_ref().peer_certificate()
+                .map_err(|err| err.to_string())?
+                .ok_or_else(|| "no certificate presented".to_owned())?;
+            certificate.to_der().map_err(|err| err.to_string())
+        }).await;
+        result.unwrap_or_else(|_| Err("timed out".to_owned()))
+    }
+
+    /// Builds a client for checks with a host or custom TLS settings, others use the shared client
+    fn http_client_for(client: &reqwest::Client, target: IpAddr, check: &HttpCheck) -> Result<reqwest::Client, reqwest::Error> {
+        if check.host.is_none() && check.tls == TlsConfig::default() {
+            return Ok(client.clone());
+        }
+        let mut builder = HealthChecker::http_client_builder();
+        if let Some(host) = &check.host {
+            // resolving the host to the target sends Host header and SNI while connecting to the target
+            builder = builder.resolve(host.as_str(), SocketAddr::new(target, check.port));
+        }
+        for certificate in &check.tls.ca_certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(certificate)?);
+        }
+        if check.tls.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some((cert, key)) = &check.tls.client_identity {
+            builder = builder.identity(Identity::from_pkcs8_pem(cert, key)?);
+        }
+        builder.build()
+    }
+
+    async fn http_check(clients: Option<HttpCheckClients>, check: &HttpCheck, uri: String, timeout: Duration, target: IpAddr, target_id: &str) -> bool {
+        let clients = match clients {
+            Some(clients) => { clients }
+            None => {
+                warn!("Target {} has no HTTP client, considering the check failed", target);
+                return false;
+            }
+        };
+        // the certificate is inspected separately, an invalid one would already fail the handshake of the request
+        if let (Some(certificate_check), Some(inspector)) = (&check.certificate, &clients.certificate_inspector) {
+            let target_host = target.to_string();
+            match HealthChecker::inspect_certificate(inspector, target, check, timeout).await {
+                Ok(der) => {
+                    if !check_certificate(der.as_slice(), certificate_check, target_host.as_str(), target_id) {
+                        return false;
+                    }
+                }
+                Err(error) => {
+                    warn!("Couldn't inspect the certificate of target {}: {}", target_host, error);
+                    TLS_CERTIFICATE_EXPIRY_DAYS.remove_label_values(&[target_host.as_str(), target_id]).ok();
+                    return false;
+                }
+            }
+        }
+        let client = clients.client;
+        debug!("Checking {} {} via {}", check.method.as_str(), uri.as_str(), target);
+        let mut request = client.request(check.method.clone(), uri)
+            .headers(check.headers.clone())
+            .timeout(timeout);
+        if let Some(body) = &check.body {
+            request = request.body(body.clone());
+        }
+        let request = request.build().unwrap();
+        match client.execute(request).await {
             Ok(response) => {
-                if response.errors.len() != 0 {
-                    response.errors.iter().for_each(|e| warn!("CF Api Error: {}", e));
-                    return;
+                let status_code = response.status();
+                debug!("Response Status: {}", status_code.as_u16());
+                let status_ok = if check.expected_status.is_empty() {
+                    status_code.is_success()
+                } else {
+                    check.expected_status.iter()
+                        .any(|range| range.contains(status_code.as_u16()))
+                };
+                if !status_ok {
+                    return false;
                 }
-                let opt_entry = response.result.iter()
-                    .find(|dns| {
-                        match dns.content {
-                            DnsContent::A { content } => { content.eq(&target.target) }
-                            _ => { false }
+                match &check.body_match {
+                    Some(body_match) => {
+                        match response.text().await {
+                            Ok(body) => {
+                                let matches = body_match.matches(body.as_str());
+                                if !matches {
+                                    debug!("Response body did not match {:?}", body_match);
+                                }
+                                matches
+                            }
+                            Err(_) => {
+                                debug!("Response body could not be read");
+                                false
+                            }
                         }
-                    });
-                if let Some(entry) = opt_entry {
-                    self.cloudflare_client.request_handle(&DeleteDnsRecord {
-                        zone_identifier: target.zone.as_str(),
-                        identifier: entry.id.as_str(),
-                    }).await.ok();
-                    CLOUDFLARE_REQUEST_COUNTER
-                        .with_label_values(&["delete_dns"])
-                        .inc();
-                    info!("Deleted cloudflare record for {} -> {}", target.dns, target.target.to_string());
+                    }
+                    None => { true }
+                }
+            }
+            Err(err) => {
+                // unlike refused connections, failed handshakes hint at a misconfigured target
+                let tls_failed = std::iter::successors(err.source(), |&source| source.source())
+                    .any(|source| source.is::<native_tls::Error>());
+                if tls_failed {
+                    warn!("TLS handshake with target {} failed: {}", target, err);
                 } else {
-                    warn!("No match on cloudflare for record {} -> {}", target.dns, target.target.to_string())
+                    debug!("Response Status: 0 (Error: {})", err);
+                }
+                false
+            }
+        }
+    }
+
+    fn provider(&self, mode: &TargetMode) -> &Arc<dyn DnsProvider> {
+        match mode {
+            TargetMode::DnsRecord => { &self.dns_provider }
+            TargetMode::LoadBalancerPool => { &self.pool_provider }
+        }
+    }
+
+    async fn add_target_record(&self, target: &ServiceTarget) {
+        let record = target.mode.describe(target.dns.as_str(), target.target);
+        if !self.leading {
+            info!("Follower not putting {} into rotation", record);
+            return;
+        }
+        let provider = self.provider(&target.mode);
+        match provider.list_records(target.zone.as_str(), target.dns.as_str()).await {
+            Ok(records) => {
+                if !records.contains(&target.target) {
+                    match provider.add_record(target.zone.as_str(), target.dns.as_str(), target.target).await {
+                        Ok(_) => {
+                            info!("Put {} into rotation", record);
+                        }
+                        Err(error) => {
+                            warn!("Couldn't put {} into rotation: {}", record, error);
+                        }
+                    }
                 }
             }
             Err(error) => {
-                warn!("Error with CF Api: {}", error);
+                warn!("{}", error);
             }
         }
     }
 
-    async fn list_dns(&self, zone: &str, dns: String) -> ApiResponse<Vec<DnsRecord>> {
-        CLOUDFLARE_REQUEST_COUNTER
-            .with_label_values(&["list_dns"])
-            .inc();
-        self.cloudflare_client.request_handle(&ListDnsRecords {
-            zone_identifier: zone,
-            params: ListDnsRecordsParams {
-                record_type: None,
-                name: Some(dns),
-                page: None,
-                per_page: Some(100),
-                order: None,
-                direction: None,
-                search_match: None,
-            },
-        }).await
+    async fn remove_target_record(&self, target: &ServiceTarget) {
+        let record = target.mode.describe(target.dns.as_str(), target.target);
+        if !self.leading {
+            info!("Follower not taking {} out of rotation", record);
+            return;
+        }
+        match self.provider(&target.mode).remove_record(target.zone.as_str(), target.dns.as_str(), target.target).await {
+            Ok(true) => {
+                info!("Took {} out of rotation", record);
+            }
+            Ok(false) => {
+                warn!("{} was already out of rotation", record)
+            }
+            Err(error) => {
+                warn!("{}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_provider::MemoryProvider;
+    use crate::leader_election::FileLease;
+    use crate::models::CheckCombinator;
+    use std::{env, fs};
+
+    const ZONE: &str = "zone";
+    const NAME: &str = "balanced.example.org";
+
+    fn target(ip: &str) -> ServiceTarget {
+        named_target(NAME, ip)
     }
-}
\ No newline at end of file
+
+    fn named_target(name: &str, ip: &str) -> ServiceTarget {
+        ServiceTarget {
+            id: format!("{}-{}", name, ip),
+            target: ip.parse().unwrap(),
+            checks: vec![ServiceUri::TcpProbe(TcpCheck { port: 443, send: None, expect: None })],
+            combinator: CheckCombinator::All,
+            mode: TargetMode::DnsRecord,
+            zone: ZONE.to_owned(),
+            dns: name.to_owned(),
+            response_threshold_ms: None,
+            interval: None,
+            jitter: Duration::from_secs(0),
+            rise: 2,
+            fall: 3,
+        }
+    }
+
+    /// Starts a health checker whose targets are all in DNS
+    async fn checker(targets: Vec<ServiceTarget>) -> (HealthChecker, Arc<MemoryProvider>) {
+        let provider = Arc::new(MemoryProvider::new());
+        for target in &targets {
+            provider.add_record(ZONE, target.dns.as_str(), target.target).await.unwrap();
+        }
+        let mut checker = HealthChecker::new(provider.clone(), Arc::new(MemoryProvider::new()), targets.clone(), None, None);
+        checker.inventory(&targets).await;
+        (checker, provider)
+    }
+
+    async fn records(provider: &MemoryProvider) -> Vec<IpAddr> {
+        named_records(provider, NAME).await
+    }
+
+    async fn named_records(provider: &MemoryProvider, name: &str) -> Vec<IpAddr> {
+        provider.list_records(ZONE, name).await.unwrap()
+    }
+
+    async fn report(checker: &mut HealthChecker, target: &ServiceTarget, up: bool, times: u32) {
+        for _ in 0..times {
+            checker.handle_check_result(target.clone(), up, Some(Duration::from_millis(1))).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn target_is_removed_after_fall_failures() {
+        let (first, second) = (target("10.0.0.1"), target("10.0.0.2"));
+        let (mut checker, provider) = checker(vec![first.clone(), second.clone()]).await;
+
+        report(&mut checker, &first, false, first.fall - 1).await;
+        assert!(records(&provider).await.contains(&first.target));
+
+        report(&mut checker, &first, false, 1).await;
+        assert_eq!(records(&provider).await, vec![second.target]);
+        assert!(checker.unavailable.contains(&first.key()));
+    }
+
+    #[tokio::test]
+    async fn target_is_added_again_after_rise_successes() {
+        let (first, second) = (target("10.0.0.1"), target("10.0.0.2"));
+        let (mut checker, provider) = checker(vec![first.clone(), second.clone()]).await;
+        report(&mut checker, &first, false, first.fall).await;
+
+        report(&mut checker, &first, true, first.rise - 1).await;
+        assert!(!records(&provider).await.contains(&first.target));
+
+        report(&mut checker, &first, true, 1).await;
+        assert!(records(&provider).await.contains(&first.target));
+        assert!(checker.unavailable.is_empty());
+    }
+
+    #[tokio::test]
+    async fn thresholds_require_consecutive_results() {
+        let (first, second) = (target("10.0.0.1"), target("10.0.0.2"));
+        let (mut checker, provider) = checker(vec![first.clone(), second.clone()]).await;
+
+        // a single success resets the failures counted so far
+        report(&mut checker, &first, false, first.fall - 1).await;
+        report(&mut checker, &first, true, 1).await;
+        report(&mut checker, &first, false, first.fall - 1).await;
+        assert!(records(&provider).await.contains(&first.target));
+
+        report(&mut checker, &first, false, 1).await;
+        report(&mut checker, &first, true, first.rise - 1).await;
+        report(&mut checker, &first, false, 1).await;
+        report(&mut checker, &first, true, first.rise - 1).await;
+        assert!(!records(&provider).await.contains(&first.target));
+    }
+
+    #[tokio::test]
+    async fn last_target_of_a_name_is_not_removed() {
+        let (first, second) = (target("10.0.0.1"), target("10.0.0.2"));
+        let (mut checker, provider) = checker(vec![first.clone(), second.clone()]).await;
+
+        report(&mut checker, &first, false, first.fall).await;
+        report(&mut checker, &second, false, second.fall).await;
+        assert_eq!(records(&provider).await, vec![second.target]);
+        assert!(!checker.unavailable.contains(&second.key()));
+
+        // the other target takes over again once it is back up
+        report(&mut checker, &first, true, first.rise).await;
+        report(&mut checker, &second, false, 1).await;
+        assert_eq!(records(&provider).await, vec![first.target]);
+    }
+
+    #[tokio::test]
+    async fn only_targets_without_record_are_unavailable() {
+        let (first, second) = (target("10.0.0.1"), target("10.0.0.2"));
+        let provider = Arc::new(MemoryProvider::new());
+        provider.add_record(ZONE, NAME, first.target).await.unwrap();
+        let targets = vec![first.clone(), second.clone()];
+        let mut checker = HealthChecker::new(provider.clone(), Arc::new(MemoryProvider::new()), targets.clone(), None, None);
+
+        checker.inventory(&targets).await;
+        assert_eq!(checker.unavailable, vec![second.key()]);
+
+        // the missing target is put into rotation once it rises
+        report(&mut checker, &second, true, second.rise).await;
+        assert_eq!(records(&provider).await, vec![first.target, second.target]);
+        assert!(checker.unavailable.is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_address_under_two_names_is_tracked_per_name() {
+        let (first, second) = (named_target("a.example.org", "10.0.0.1"), named_target("a.example.org", "10.0.0.2"));
+        let (other, other_second) = (named_target("b.example.org", "10.0.0.1"), named_target("b.example.org", "10.0.0.3"));
+        let (mut checker, provider) = checker(vec![first.clone(), second.clone(), other.clone(), other_second.clone()]).await;
+
+        report(&mut checker, &first, false, first.fall).await;
+        assert_eq!(named_records(&provider, "a.example.org").await, vec![second.target]);
+        assert_eq!(named_records(&provider, "b.example.org").await, vec![other.target, other_second.target]);
+        assert_eq!(checker.unavailable, vec![first.key()]);
+
+        // the address rising for one name doesn't count for the other
+        report(&mut checker, &other, true, other.rise).await;
+        assert!(!named_records(&provider, "a.example.org").await.contains(&first.target));
+    }
+
+    #[tokio::test]
+    async fn drains_are_kept_when_the_leader_changes() {
+        let (first, second) = (target("10.0.0.1"), target("10.0.0.2"));
+        let targets = vec![first.clone(), second.clone()];
+        let temp_path = |name: &str| {
+            let path = env::temp_dir().join(format!("dns-balancer-{}-{}", name, std::process::id()));
+            fs::remove_file(&path).ok();
+            path.to_string_lossy().into_owned()
+        };
+        let (lease_path, state_path) = (temp_path("takeover.lock"), temp_path("takeover.json"));
+        let provider = Arc::new(MemoryProvider::new());
+        for target in &targets {
+            provider.add_record(ZONE, NAME, target.target).await.unwrap();
+        }
+        let replica = || {
+            let leader_election: Arc<dyn LeaderElection> = Arc::new(FileLease::new(lease_path.clone()));
+            HealthChecker::new(provider.clone(), Arc::new(MemoryProvider::new()), targets.clone(),
+                               Some(StateFile::new(state_path.clone())), Some(leader_election))
+        };
+        let (mut leader, mut follower) = (replica(), replica());
+        assert!(leader.elect_leader().await);
+        assert!(!follower.elect_leader().await);
+
+        leader.drain_target(first.id.clone()).await;
+        leader.save_state().await;
+        // the follower doesn't know about the drain and must not overwrite the state of the leader
+        report(&mut follower, &second, true, 1).await;
+        follower.save_state().await;
+        assert_eq!(StateFile::new(state_path.clone()).load().drained, vec![first.id.clone()]);
+        assert_eq!(records(&provider).await, vec![second.target]);
+
+        // the lease is released once the leader exits
+        drop(leader);
+        assert!(follower.elect_leader().await);
+        follower.take_over_drains().await;
+        follower.reconcile().await;
+        assert_eq!(follower.drained, vec![first.id.clone()]);
+        report(&mut follower, &first, true, first.rise).await;
+        assert_eq!(records(&provider).await, vec![second.target]);
+
+        fs::remove_file(lease_path).ok();
+        fs::remove_file(state_path).ok();
+    }
+}
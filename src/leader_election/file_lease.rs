@@ -0,0 +1,131 @@
+use crate::leader_election::{ElectionError, LeaderElection};
+use async_trait::async_trait;
+use fs2::FileExt;
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Leads as long as an exclusive lock on the file is held, which is released when the process exits.
+/// Only works for replicas on the same host or a shared file system supporting locks
+pub struct FileLease {
+    path: String,
+    file: Mutex<Option<File>>,
+}
+
+impl FileLease {
+    pub fn new(path: String) -> FileLease {
+        FileLease {
+            path,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Whether the locked file is still the one at the path, a removed or replaced file could be locked by another replica
+    #[cfg(unix)]
+    fn is_current(&self, locked: &File) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        match (locked.metadata(), fs::metadata(self.path.as_str())) {
+            (Ok(locked), Ok(current)) => { locked.dev() == current.dev() && locked.ino() == current.ino() }
+            _ => { false }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn is_current(&self, _locked: &File) -> bool {
+        fs::metadata(self.path.as_str()).is_ok()
+    }
+}
+
+#[async_trait]
+impl LeaderElection for FileLease {
+    async fn try_lead(&self) -> Result<bool, ElectionError> {
+        let mut file = self.file.lock().unwrap();
+        if let Some(locked) = file.as_ref() {
+            if self.is_current(locked) {
+                return Ok(true);
+            }
+            warn!("The lease file {} was removed or replaced, acquiring it again", self.path);
+            // releases the lock of the old file
+            *file = None;
+        }
+        let mut lease = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.as_str())
+            .map_err(|error| ElectionError::new(format!("Couldn't open the lease file {}: {}", self.path, error)))?;
+        if let Err(error) = lease.try_lock_exclusive() {
+            return if error.kind() == fs2::lock_contended_error().kind() {
+                Ok(false)
+            } else {
+                Err(ElectionError::new(format!("Couldn't lock the lease file {}: {}", self.path, error)))
+            };
+        }
+        // the holder is only written for humans, the lock itself decides
+        lease.set_len(0)
+            .and_then(|_| writeln!(lease, "process {}", std::process::id()))
+            .map_err(|error| ElectionError::new(format!("Couldn't write the lease file {}: {}", self.path, error)))?;
+        *file = Some(lease);
+        Ok(true)
+    }
+
+    async fn leader(&self) -> Option<String> {
+        fs::read_to_string(self.path.as_str()).ok()
+            .map(|holder| holder.trim().to_owned())
+            .filter(|holder| !holder.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn lease_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("dns-balancer-{}-{}.lock", name, std::process::id()));
+        fs::remove_file(&path).ok();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn only_one_lease_leads() {
+        let path = lease_path("exclusive");
+        let first = FileLease::new(path.clone());
+        let second = FileLease::new(path.clone());
+
+        assert!(first.try_lead().await.unwrap());
+        assert!(!second.try_lead().await.unwrap());
+        assert_eq!(second.leader().await, Some(format!("process {}", std::process::id())));
+        // renewing keeps the leadership
+        assert!(first.try_lead().await.unwrap());
+        assert!(!second.try_lead().await.unwrap());
+        fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn released_lease_is_taken_over() {
+        let path = lease_path("released");
+        let first = FileLease::new(path.clone());
+        let second = FileLease::new(path.clone());
+        assert!(first.try_lead().await.unwrap());
+        assert!(!second.try_lead().await.unwrap());
+
+        drop(first);
+        assert!(second.try_lead().await.unwrap());
+        fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn replaced_lease_file_is_locked_again() {
+        let path = lease_path("replaced");
+        let first = FileLease::new(path.clone());
+        let second = FileLease::new(path.clone());
+        assert!(first.try_lead().await.unwrap());
+
+        fs::remove_file(path.as_str()).unwrap();
+        assert!(second.try_lead().await.unwrap());
+        assert!(!first.try_lead().await.unwrap());
+        fs::remove_file(path).ok();
+    }
+}
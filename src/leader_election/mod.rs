@@ -0,0 +1,37 @@
+mod file_lease;
+
+pub use self::file_lease::FileLease;
+
+use async_trait::async_trait;
+use std::fmt;
+
+/// Store deciding which of several replicas may change the DNS records
+#[async_trait]
+pub trait LeaderElection: Send + Sync {
+    /// Acquires or renews the leadership, returns whether this replica is the leader
+    async fn try_lead(&self) -> Result<bool, ElectionError>;
+
+    /// Describes the current leader for followers, if known
+    async fn leader(&self) -> Option<String>;
+}
+
+#[derive(Debug)]
+pub struct ElectionError {
+    message: String,
+}
+
+impl ElectionError {
+    pub fn new<S: Into<String>>(message: S) -> ElectionError {
+        ElectionError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ElectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message.as_str())
+    }
+}
+
+impl std::error::Error for ElectionError {}
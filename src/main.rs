@@ -2,6 +2,13 @@ mod models;
 mod health_checker;
 mod config;
 mod metrics;
+mod dns_provider;
+mod admin;
+mod certificate;
+mod state;
+mod leader_election;
+#[cfg(unix)]
+mod reload;
 
 #[cfg(not(target_env = "msvc"))]
 use jemallocator::Jemalloc;
@@ -9,13 +16,16 @@ use log::{info, warn};
 use cloudflare::framework::async_api::Client;
 use cloudflare::framework::auth::Credentials;
 use cloudflare::framework::{HttpApiClientConfig, Environment};
-use std::env;
-use crate::health_checker::HealthChecker;
+use crate::health_checker::{HealthChecker, Command};
 use std::process::exit;
-use tokio::time::Duration;
 use crate::config::Config;
-use std::net::{SocketAddr, IpAddr};
-use std::str::FromStr;
+use crate::state::StateFile;
+use crate::leader_election::{LeaderElection, FileLease};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use warp::Filter;
+use crate::dns_provider::{DnsProvider, CloudflareProvider, CloudflarePoolProvider, DryRunProvider, MemoryProvider};
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
@@ -41,40 +51,93 @@ async fn main() {
         build_time
     );
 
-    let token = env::var("CF_TOKEN").expect("Please provide a `CF_TOKEN` in env!");
-    let client = Client::new(
-        Credentials::UserAuthToken { token },
-        HttpApiClientConfig::default(),
-        Environment::Production,
-    ).expect("Couldn't construct the CloudFlare API Client... Panic!");
+    let config_path = Config::config_path_from_args();
+    let config = Config::load(config_path.clone());
 
-    let service_data = env::var("SERVICE_TARGETS")
-        .expect("Please provide a `SERVICE_TARGETS` in env!");
-    let service_targets = Config::read_service_targets(service_data.as_str());
-
-    let check_interval = env::var("CHECK_INTERVAL")
-        .map(|str| str.parse().unwrap())
-        .map(|dur| Duration::from_secs(dur))
-        .unwrap_or(Duration::from_secs(30));
+    let (dns_provider, pool_provider): (Arc<dyn DnsProvider>, Arc<dyn DnsProvider>) = match config.dns_provider.to_lowercase().as_str() {
+        "cloudflare" => {
+            let token = config.cf_token.clone()
+                .expect("Please provide a `CF_TOKEN` in env or a cloudflare token in the config file!");
+            let cloudflare_client = || {
+                Client::new(
+                    Credentials::UserAuthToken { token: token.clone() },
+                    HttpApiClientConfig::default(),
+                    Environment::Production,
+                ).expect("Couldn't construct the CloudFlare API Client... Panic!")
+            };
+            (
+                Arc::new(CloudflareProvider::new(cloudflare_client())),
+                Arc::new(CloudflarePoolProvider::new(cloudflare_client())),
+            )
+        }
+        "memory" => {
+            warn!("Using the in-memory DNS provider, no actual DNS records will be changed");
+            (Arc::new(MemoryProvider::new()), Arc::new(MemoryProvider::new()))
+        }
+        _ => {
+            panic!("Invalid DNS_PROVIDER provided, please use Cloudflare or Memory")
+        }
+    };
+    let (dns_provider, pool_provider): (Arc<dyn DnsProvider>, Arc<dyn DnsProvider>) = if config.dry_run {
+        warn!("Running in dry run mode, DNS changes are only logged");
+        (Arc::new(DryRunProvider::new(dns_provider)), Arc::new(DryRunProvider::new(pool_provider)))
+    } else {
+        (dns_provider, pool_provider)
+    };
 
-    let health_checker = HealthChecker::new(client, service_targets)
-        .run(check_interval);
+    let (command_sender, command_receiver) = mpsc::channel::<Command>(16);
+    #[cfg(unix)]
+    {
+        if let Some(path) = config_path {
+            reload::watch_config(path, command_sender.clone());
+        }
+    }
 
-    let prometheus_enabled = env::var("PROMETHEUS_ENABLED")
-        .map(|str| str.parse().unwrap())
-        .unwrap_or(false);
+    let state_file = config.state_file.map(StateFile::new);
+    let leader_election = config.leader_lease_file.map(|path| {
+        info!("Electing a leader with the lease file {}", path);
+        Arc::new(FileLease::new(path)) as Arc<dyn LeaderElection>
+    });
+    let health_checker = HealthChecker::new(dns_provider, pool_provider, config.service_targets, state_file, leader_election);
+    let status = health_checker.status();
+    let leadership = health_checker.leadership();
+    let reconcile_interval = if config.reconcile_every > 0 {
+        Some(config.check_interval * config.reconcile_every)
+    } else {
+        None
+    };
+    let health_checker = health_checker.run(config.check_interval, reconcile_interval, command_receiver);
 
-    if prometheus_enabled {
-        info!("Starting prometheus server");
-        let prometheus_port = env::var("PROMETHEUS_PORT")
-            .map(|str| str.parse().unwrap())
-            .unwrap_or(8080);
+    if config.prometheus_enabled || config.admin_enabled {
+        let prometheus_addr = SocketAddr::new(config.prometheus_host, config.prometheus_port);
+        let admin = if config.admin_enabled {
+            info!("Starting admin api");
+            Some(admin::admin_filter(status, leadership, command_sender, config.admin_token))
+        } else {
+            None
+        };
+        let prometheus_enabled = config.prometheus_enabled;
         tokio::spawn(async move {
             // stupid warp has no error return
-            let ip = env::var("PROMETHEUS_HOST").unwrap_or("0.0.0.0".to_owned());
-            warp::serve(metrics::metrics_filter())
-                .run(SocketAddr::new(IpAddr::from_str(ip.as_str()).unwrap(), prometheus_port))
-                .await;
+            match admin {
+                Some(admin) if prometheus_enabled => {
+                    info!("Starting prometheus server");
+                    warp::serve(metrics::metrics_filter().or(admin))
+                        .run(prometheus_addr)
+                        .await;
+                }
+                Some(admin) => {
+                    warp::serve(admin)
+                        .run(prometheus_addr)
+                        .await;
+                }
+                None => {
+                    info!("Starting prometheus server");
+                    warp::serve(metrics::metrics_filter())
+                        .run(prometheus_addr)
+                        .await;
+                }
+            }
         });
     }
 
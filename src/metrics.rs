@@ -1,8 +1,8 @@
 use lazy_static::lazy_static;
 
 use prometheus::{
-    self, register_histogram_vec, register_int_counter_vec, register_int_gauge,
-    register_int_gauge_vec, Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    self, register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
     TextEncoder,
 };
 use warp::{http, Filter};
@@ -15,6 +15,26 @@ lazy_static! {
     )
     .unwrap();
 
+    pub static ref DRY_RUN_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "dns_balancer_dry_run_changes",
+        "Record changes which would have been made without dry run, by action and name",
+        &["action", "name"]
+    )
+    .unwrap();
+
+    pub static ref DRIFT_CORRECTIONS: IntCounterVec = register_int_counter_vec!(
+        "dns_balancer_drift_corrections",
+        "Records which differed from the available targets and were added or removed by reconciliation",
+        &["action"]
+    )
+    .unwrap();
+
+    pub static ref LEADER: IntGauge = register_int_gauge!(
+        "dns_balancer_leader",
+        "Whether this replica is the leader and changes the DNS records: 1 Leader - 0 Follower"
+    )
+    .unwrap();
+
     pub static ref TARGETS_AVAILABLE: IntGauge = register_int_gauge!(
         "dns_balancer_targets_available",
         "Amount of online targets"
@@ -24,14 +44,63 @@ lazy_static! {
     pub static ref TARGETS_STATUS: IntGaugeVec = register_int_gauge_vec!(
         "dns_balancer_targets_status",
         "Status per target: 1 Online - 0 Offline",
-        &["target"]
+        &["target", "id"]
+    )
+    .unwrap();
+
+    pub static ref TARGETS_CONSECUTIVE_SUCCESSES: IntGaugeVec = register_int_gauge_vec!(
+        "dns_balancer_targets_consecutive_successes",
+        "Consecutive successful checks per target, compared against the rise threshold",
+        &["target", "id"]
+    )
+    .unwrap();
+
+    pub static ref TARGETS_CONSECUTIVE_FAILURES: IntGaugeVec = register_int_gauge_vec!(
+        "dns_balancer_targets_consecutive_failures",
+        "Consecutive failed checks per target, compared against the fall threshold",
+        &["target", "id"]
+    )
+    .unwrap();
+
+    pub static ref CHECK_STATUS: IntGaugeVec = register_int_gauge_vec!(
+        "dns_balancer_check_status",
+        "Result of the last run of each check of a target: 1 Passed - 0 Failed",
+        &["target", "id", "check"]
+    )
+    .unwrap();
+
+    pub static ref TLS_CERTIFICATE_EXPIRY_DAYS: GaugeVec = register_gauge_vec!(
+        "dns_balancer_tls_certificate_expiry_days",
+        "Days until the certificate presented by a HTTPS target expires",
+        &["target", "id"]
+    )
+    .unwrap();
+
+    pub static ref ICMP_RTT: GaugeVec = register_gauge_vec!(
+        "dns_balancer_icmp_rtt_ms",
+        "Average round trip time of the echo requests of the last ICMP check per target",
+        &["target", "id"]
+    )
+    .unwrap();
+
+    pub static ref ICMP_LOSS: GaugeVec = register_gauge_vec!(
+        "dns_balancer_icmp_loss_percent",
+        "Percentage of lost echo requests of the last ICMP check per target",
+        &["target", "id"]
+    )
+    .unwrap();
+
+    pub static ref TCP_CONNECT_TIME: GaugeVec = register_gauge_vec!(
+        "dns_balancer_tcp_connect_time_ms",
+        "Time until the connection of the last TCP check per target was established",
+        &["target", "id"]
     )
     .unwrap();
 
     pub static ref HEALTHCHECK_REQUEST_TIME: HistogramVec = register_histogram_vec!(
         "dns_balancer_healthcheck_request_time",
         "Used for quantiles over the average healthcheck request time",
-        &["target"],
+        &["target", "id"],
         prometheus::exponential_buckets(0.01, 1.8, 20).unwrap()
     )
     .unwrap();
@@ -1,31 +1,252 @@
-use std::net::Ipv4Addr;
-use warp::http::Method;
+use std::net::IpAddr;
+use warp::http::{HeaderMap, Method};
+use std::collections::HashMap;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use regex::Regex;
+use trust_dns_client::rr::{Name, RecordType};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ServiceTarget {
-    pub target: Ipv4Addr,
-    pub check: ServiceUri,
+    /// Identifies the target in the admin API
+    pub id: String,
+    pub target: IpAddr,
+    pub checks: Vec<ServiceUri>,
+    /// Decides how many of the checks have to pass for the target to be considered up
+    pub combinator: CheckCombinator,
+    pub mode: TargetMode,
+    /// The zone, or the account of a load balancer pool
     pub zone: String,
+    /// The DNS name, or the identifier of a load balancer pool
     pub dns: String,
     pub response_threshold_ms: Option<u32>,
+    /// Falls back to the global check interval
+    pub interval: Option<Duration>,
+    /// Upper bound of the random delay added to every interval
+    pub jitter: Duration,
+    /// Consecutive successful checks required before a target is considered up again
+    pub rise: u32,
+    /// Consecutive failed checks required before a target is considered down
+    pub fall: u32,
+}
+
+impl ServiceTarget {
+    /// Identifies the state of the target, the same address may be balanced for several names
+    pub fn key(&self) -> TargetKey {
+        (self.mode.clone(), self.zone.clone(), self.dns.clone(), self.target)
+    }
+}
+
+/// Mode, zone, name and address of a target
+pub type TargetKey = (TargetMode, String, String, IpAddr);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckCombinator {
+    All,
+    Any,
+    AtLeast(usize),
+}
+
+impl CheckCombinator {
+    pub fn is_up(&self, passed: usize, total: usize) -> bool {
+        match self {
+            CheckCombinator::All => { passed == total }
+            CheckCombinator::Any => { passed > 0 }
+            CheckCombinator::AtLeast(required) => { passed >= *required }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetMode {
+    /// Adds and deletes A or AAAA records of the DNS name
+    DnsRecord,
+    /// Enables and disables the origin with the target's address in a Cloudflare Load Balancing pool
+    LoadBalancerPool,
+}
+
+impl TargetMode {
+    /// Describes the record or pool origin of an address for logs
+    pub fn describe(&self, name: &str, ip: IpAddr) -> String {
+        match self {
+            TargetMode::DnsRecord => { format!("DNS record {} -> {}", name, ip) }
+            TargetMode::LoadBalancerPool => { format!("origin {} of pool {}", ip, name) }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ServiceUri {
-    Icmp,
-    TcpProbe(u16),
-    Http(u16, Method, String),
-    HttpSecure(u16, Method, String),
+    Icmp(IcmpCheck),
+    TcpProbe(TcpCheck),
+    Http(HttpCheck),
+    HttpSecure(HttpCheck),
+    Grpc(GrpcCheck),
+    Dns(DnsCheck),
+    Exec(ExecCheck),
+}
+
+impl ServiceUri {
+    /// Short name of the check type, used in logs and metric labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServiceUri::Icmp(_) => { "icmp" }
+            ServiceUri::TcpProbe(_) => { "tcpprobe" }
+            ServiceUri::Http(_) => { "http" }
+            ServiceUri::HttpSecure(_) => { "https" }
+            ServiceUri::Grpc(_) => { "grpc" }
+            ServiceUri::Dns(_) => { "dns" }
+            ServiceUri::Exec(_) => { "exec" }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IcmpCheck {
+    /// Amount of echo requests sent per check
+    pub count: u32,
+    /// The target is considered down if more echo requests are lost
+    pub max_loss_percent: f64,
+    /// The target is considered down if the average round trip time is higher
+    pub max_rtt_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpCheck {
+    pub port: u16,
+    /// Written to the connection once it is established
+    pub send: Option<String>,
+    /// Has to be contained in the data received from the target
+    pub expect: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcCheck {
+    pub port: u16,
+    /// Service name sent in the health check request, empty for the overall server health
+    pub service: String,
+    pub tls: bool,
+    /// Name the TLS certificate is verified against
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsCheck {
+    pub port: u16,
+    pub name: Name,
+    pub record_type: RecordType,
+    pub protocol: DnsProtocol,
+    /// Has to match one of the records in the answer section if set
+    pub expected_answer: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecCheck {
+    pub command: String,
+    /// `{ip}` and `{port}` are replaced with the target address
+    pub args: Vec<String>,
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpCheck {
+    pub port: u16,
+    pub method: Method,
+    pub route: String,
+    /// Sent with every request, including the content type of the body
+    pub headers: HeaderMap,
+    pub body: Option<String>,
+    /// Accepted status codes, any 2xx status is accepted if empty
+    pub expected_status: Vec<StatusRange>,
+    pub body_match: Option<BodyMatch>,
+    /// Sent as Host header and TLS SNI while still connecting to the target address
+    pub host: Option<String>,
+    /// Only inspected for HTTPS checks
+    pub certificate: Option<CertificateCheck>,
+    pub tls: TlsConfig,
+}
+
+/// Trust settings of HTTPS checks
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TlsConfig {
+    /// PEM encoded certificates trusted in addition to the system roots
+    pub ca_certificates: Vec<Vec<u8>>,
+    pub insecure_skip_verify: bool,
+    /// PEM encoded client certificate and PKCS#8 private key for mutual TLS
+    pub client_identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateCheck {
+    /// A warning is logged if the certificate expires within this amount of days
+    pub warn_days: u32,
+    /// The target is considered down if the certificate expires within this amount of days
+    pub fail_days: u32,
+    /// The name the certificate has to be valid for
+    pub hostname: Option<String>,
+}
+
+/// Inclusive range of HTTP status codes
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusRange {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl StatusRange {
+    pub fn contains(&self, status: u16) -> bool {
+        self.from <= status && status <= self.to
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BodyMatch {
+    Contains(String),
+    Regex(Regex),
+}
+
+impl BodyMatch {
+    pub fn matches(&self, body: &str) -> bool {
+        match self {
+            BodyMatch::Contains(needle) => { body.contains(needle.as_str()) }
+            BodyMatch::Regex(regex) => { regex.is_match(body) }
+        }
+    }
+}
+
+impl PartialEq for BodyMatch {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (BodyMatch::Contains(a), BodyMatch::Contains(b)) => { a.eq(b) }
+            (BodyMatch::Regex(a), BodyMatch::Regex(b)) => { a.as_str().eq(b.as_str()) }
+            _ => { false }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SerializedServiceTarget {
+    pub id: Option<String>,
     pub ip: String,
-    pub cf_zone: String,
-    pub cf_dns: String,
-    pub check: SerializedServiceUri,
+    pub mode: Option<String>,
+    pub cf_zone: Option<String>,
+    pub cf_dns: Option<String>,
+    pub cf_account: Option<String>,
+    pub cf_pool: Option<String>,
+    pub check: Option<SerializedServiceUri>,
+    pub checks: Option<Vec<SerializedServiceUri>>,
+    pub combinator: Option<SerializedCombinator>,
     pub response_threshold_ms: Option<u32>,
+    pub interval: Option<u64>,
+    pub jitter_ms: Option<u64>,
+    pub rise: Option<u32>,
+    pub fall: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -34,4 +255,117 @@ pub struct SerializedServiceUri {
     pub port: Option<u16>,
     pub method: Option<String>,
     pub route: Option<String>,
+    /// Values may reference an env variable as `env:NAME` or a file as `file:PATH`
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    pub expected_status: Option<Vec<SerializedStatusCode>>,
+    pub body_contains: Option<String>,
+    pub body_regex: Option<String>,
+    pub host: Option<String>,
+    pub cert_warn_days: Option<u32>,
+    pub cert_fail_days: Option<u32>,
+    pub cert_hostname: Option<String>,
+    pub ca_bundle: Option<String>,
+    pub insecure_skip_verify: Option<bool>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub count: Option<u32>,
+    pub max_loss_percent: Option<f64>,
+    pub max_rtt_ms: Option<f64>,
+    pub send: Option<String>,
+    pub expect: Option<String>,
+    pub service: Option<String>,
+    pub tls: Option<bool>,
+    pub query_name: Option<String>,
+    pub record_type: Option<String>,
+    pub protocol: Option<String>,
+    pub expected_answer: Option<String>,
+    pub command: Option<String>,
+    pub args: Option<Vec<String>>,
+}
+
+/// Either `"all"`, `"any"` or the amount of checks which have to pass
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum SerializedCombinator {
+    AtLeast(usize),
+    Name(String),
+}
+
+/// Either a single status code like `200` or a range like `"301-302"`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum SerializedStatusCode {
+    Code(u16),
+    Range(String),
+}
+
+/// Contents of the config file passed with `--config`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SerializedConfig {
+    pub targets: Option<Vec<SerializedServiceTarget>>,
+    pub check_interval: Option<u64>,
+    pub reconcile_every: Option<u32>,
+    pub dns_provider: Option<String>,
+    pub dry_run: Option<bool>,
+    pub state_file: Option<String>,
+    pub leader_lease_file: Option<String>,
+    pub cloudflare: Option<SerializedCloudflareConfig>,
+    pub prometheus: Option<SerializedPrometheusConfig>,
+    pub admin: Option<SerializedAdminConfig>,
+    pub tls: Option<SerializedTlsConfig>,
+}
+
+/// The API token can be given directly or as a reference to an env variable or a file
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SerializedCloudflareConfig {
+    pub token: Option<String>,
+    pub token_env: Option<String>,
+    pub token_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SerializedPrometheusConfig {
+    pub enabled: Option<bool>,
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+/// Defaults for the trust settings of HTTPS checks, each can be overridden per check
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SerializedTlsConfig {
+    pub ca_bundle: Option<String>,
+    pub insecure_skip_verify: Option<bool>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// The admin API is served next to the metrics, the token protects drain and undrain
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SerializedAdminConfig {
+    pub enabled: Option<bool>,
+    pub token: Option<String>,
+}
+
+/// Contents of the state file, see `STATE_FILE`
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct SerializedState {
+    pub targets: Vec<SerializedTargetState>,
+    pub drained: Vec<String>,
+}
+
+/// Health of a target, only restored if its id, zone, name and address are unchanged
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SerializedTargetState {
+    pub id: String,
+    pub zone: String,
+    pub dns: String,
+    pub ip: IpAddr,
+    pub healthy: Option<bool>,
+    pub successes: u32,
+    pub failures: u32,
+    pub last_result: Option<bool>,
+    /// Unix timestamp in seconds of the last change of `healthy`
+    pub last_transition: Option<u64>,
 }
@@ -0,0 +1,59 @@
+use crate::config::Config;
+use crate::health_checker::Command;
+use log::{info, warn};
+use std::{env, fs};
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the targets of the config file on SIGHUP or whenever the file is modified, unless `SERVICE_TARGETS` is set
+pub fn watch_config(path: String, sender: Sender<Command>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("Couldn't listen for SIGHUP");
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut last_modified = modified_time(path.as_str());
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading {}", path);
+                }
+                _ = interval.tick() => {
+                    if modified_time(path.as_str()) == last_modified {
+                        continue;
+                    }
+                    info!("{} was modified, reloading", path);
+                }
+            }
+            last_modified = modified_time(path.as_str());
+            // the targets from env replace the ones of the file, reloading would apply them again
+            if env::var("SERVICE_TARGETS").is_ok() {
+                warn!("Not reloading {}, its targets are replaced by SERVICE_TARGETS", path);
+                continue;
+            }
+
+            // loading panics on invalid configs, which must not stop the running balancer
+            let config_path = path.clone();
+            match tokio::task::spawn_blocking(move || Config::load(Some(config_path))).await {
+                Ok(config) => {
+                    if sender.send(Command::Reload(config.service_targets)).await.is_err() {
+                        warn!("Health checker stopped, no longer watching {}", path);
+                        return;
+                    }
+                }
+                Err(_) => {
+                    warn!("Invalid config in {}, keeping the current targets", path);
+                }
+            }
+        }
+    })
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
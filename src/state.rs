@@ -0,0 +1,52 @@
+use crate::models::SerializedState;
+use log::{info, warn};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+
+/// JSON file keeping the health of the targets across restarts
+#[derive(Clone)]
+pub struct StateFile {
+    path: String,
+}
+
+impl StateFile {
+    pub fn new(path: String) -> StateFile {
+        StateFile { path }
+    }
+
+    /// Reads the state, a missing or invalid file results in an empty state
+    pub fn load(&self) -> SerializedState {
+        match fs::read_to_string(self.path.as_str()) {
+            Ok(data) => {
+                serde_json::from_str(data.as_str()).unwrap_or_else(|error| {
+                    warn!("Ignoring invalid state file {}: {}", self.path, error);
+                    SerializedState::default()
+                })
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                info!("State file {} does not exist yet, starting without previous state", self.path);
+                SerializedState::default()
+            }
+            Err(error) => {
+                warn!("Couldn't read the state file {}: {}", self.path, error);
+                SerializedState::default()
+            }
+        }
+    }
+
+    /// Replaces the file by renaming a temporary one once it is synced to disk, so it is never left partially written
+    pub fn save(&self, state: &SerializedState) {
+        let temp_path = format!("{}.tmp", self.path);
+        let result = serde_json::to_vec_pretty(state)
+            .map_err(io::Error::from)
+            .and_then(|data| {
+                let mut file = File::create(temp_path.as_str())?;
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(temp_path.as_str(), self.path.as_str()));
+        if let Err(error) = result {
+            warn!("Couldn't write the state file {}: {}", self.path, error);
+        }
+    }
+}